@@ -0,0 +1,191 @@
+// Running a directory of snippets and comparing what they do with the
+// expected output kept next to them, in `foo.expected` for `foo.rs`.
+// Each file is run by another runner process, so that its `//:` comment
+// is honoured, in the file's directory.
+use anyhow::{bail, Context, Result};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Mutex;
+use std::thread;
+
+struct Expected {
+    // None if the program was killed by a signal
+    status: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+const STDOUT: &str = "--- stdout";
+const STDERR: &str = "--- stderr";
+
+// thread ids in panic messages change with every run
+fn without_thread_ids(text: &str) -> String {
+    let mut res = String::new();
+    for line in text.lines() {
+        match line.find("' (").map(|pos| (pos, &line[pos + 3..])) {
+            Some((pos, rest)) if line.starts_with("thread '") && rest.contains(") panicked") => {
+                res.push_str(&line[..pos + 1]);
+                res.push_str(&rest[rest.find(')').unwrap() + 1..]);
+            }
+            _ => res.push_str(line),
+        }
+        res.push('\n');
+    }
+    res
+}
+
+impl Expected {
+    fn new(output: &Output) -> Expected {
+        Expected {
+            status: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: without_thread_ids(&String::from_utf8_lossy(&output.stderr)),
+        }
+    }
+
+    fn parse(text: &str) -> Option<Expected> {
+        let status = text.lines().next()?.strip_prefix("status: ")?;
+        let status = if status == "killed" {
+            None
+        } else {
+            Some(status.parse().ok()?)
+        };
+        let rest = &text[text.find(STDOUT)? + STDOUT.len() + 1..];
+        let (stdout, stderr) = rest.split_once(&format!("{}\n", STDERR))?;
+        Some(Expected {
+            status,
+            stdout: stdout.to_string(),
+            stderr: stderr.to_string(),
+        })
+    }
+
+    fn to_text(&self) -> String {
+        let status = match self.status {
+            Some(code) => code.to_string(),
+            None => "killed".to_string(),
+        };
+        let mut text = format!("status: {}\n{}\n{}", status, STDOUT, self.stdout);
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text += STDERR;
+        text.push('\n');
+        text += &self.stderr;
+        text
+    }
+
+    // which parts differ, if any
+    fn differences(&self, other: &Expected) -> Vec<&'static str> {
+        let mut res = Vec::new();
+        if self.status != other.status {
+            res.push("status");
+        }
+        if self.stdout.trim_end() != other.stdout.trim_end() {
+            res.push("stdout");
+        }
+        if self.stderr.trim_end() != other.stderr.trim_end() {
+            res.push("stderr");
+        }
+        res
+    }
+}
+
+fn snippets(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("cannot read {}", dir.display()))? {
+        let path = entry?.path();
+        // env.rs is part of the prelude for the others
+        if path.extension().is_some_and(|e| e == "rs") && !path.ends_with("env.rs") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn run_snippet(runner: &Path, file: &Path) -> Result<Output> {
+    let dir = file.parent().context("file has no directory")?;
+    Command::new(runner)
+        .arg(file.file_name().unwrap())
+        .current_dir(dir)
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("cannot run {}", file.display()))
+}
+
+// run all the snippets in `dir`, printing a report, and return true if they
+// all did as expected. With `bless`, the expected files are written instead.
+pub fn batch(dir: &Path, bless: bool) -> Result<bool> {
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+    let runner = env::current_exe().context("cannot find runner")?;
+    let files = snippets(dir)?;
+    let jobs = thread::available_parallelism().map_or(1, |n| n.get());
+
+    // a few workers take files from the queue in turn
+    let queue = Mutex::new(files.iter().enumerate());
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(files.len()) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let Some((i, file)) = next else { break };
+                let output = run_snippet(&runner, file);
+                results.lock().unwrap().push((i, output));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+
+    let (mut passed, mut failed) = (0, 0);
+    for ((_, output), file) in results.into_iter().zip(&files) {
+        let actual = Expected::new(&output?);
+        let expected_file = file.with_extension("expected");
+        let name = file.file_name().unwrap().to_string_lossy();
+        if bless {
+            fs::write(&expected_file, actual.to_text())
+                .with_context(|| format!("cannot write {}", expected_file.display()))?;
+            println!("{} ... blessed", name);
+            passed += 1;
+            continue;
+        }
+        let expected = match fs::read_to_string(&expected_file) {
+            Ok(text) => Expected::parse(&text)
+                .with_context(|| format!("bad expected file {}", expected_file.display()))?,
+            Err(_) => {
+                println!("{} ... FAILED (no {})", name, expected_file.display());
+                failed += 1;
+                continue;
+            }
+        };
+        let differences = expected.differences(&actual);
+        if differences.is_empty() {
+            println!("{} ... ok", name);
+            passed += 1;
+            continue;
+        }
+        println!("{} ... FAILED ({} differ)", name, differences.join(", "));
+        for part in differences {
+            let (expected, actual) = match part {
+                "status" => (
+                    format!("{:?}\n", expected.status),
+                    format!("{:?}\n", actual.status),
+                ),
+                "stdout" => (expected.stdout.clone(), actual.stdout.clone()),
+                _ => (expected.stderr.clone(), actual.stderr.clone()),
+            };
+            print!(
+                "--- expected {}\n{}--- actual {}\n{}",
+                part, expected, part, actual
+            );
+        }
+        failed += 1;
+    }
+    println!("\n{}: {} passed; {} failed", dir.display(), passed, failed);
+    Ok(failed == 0)
+}
@@ -0,0 +1,190 @@
+// Micro-benchmarks. The generated program warms up the snippet body,
+// then times it in batches and reports the time per iteration of each
+// batch on stdout after a marker; these samples are summarized here.
+use crate::program::Program;
+use crate::state::{SnippetOptions, State};
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process;
+
+const MARKER: &str = "\u{1}runner-bench";
+
+// `main` for the benchmark wrapper. The warm-up also finds a batch size
+// which takes about a millisecond, so that very fast bodies can be timed.
+pub const MAIN: &str = "fn main() {
+    use std::time::{Duration, Instant};
+    let args: Vec<String> = std::env::args().collect();
+    let mut body = || {
+        if let Err(e) = std::hint::black_box(run(&args)) {
+            eprintln!(\"error: {}\",e);
+            std::process::exit(1);
+        }
+    };
+    let start = Instant::now();
+    let mut warmup: u128 = 0;
+    while start.elapsed() < Duration::from_millis(200) {
+        body();
+        warmup += 1;
+    }
+    let batch = (warmup * 1_000_000 / start.elapsed().as_nanos()).max(1) as u64;
+    let mut samples = Vec::new();
+    let start = Instant::now();
+    while samples.len() < 5 || (samples.len() < 1000 && start.elapsed() < Duration::from_secs(1)) {
+        let t = Instant::now();
+        for _ in 0..batch {
+            body();
+        }
+        samples.push((t.elapsed().as_nanos() as f64 / batch as f64).to_string());
+    }
+    println!(\"\\u{1}runner-bench {} {}\", batch, samples.join(\" \"));
+}
+";
+
+pub struct Stats {
+    pub iterations: u64,
+    // all in nanoseconds per iteration
+    pub mean: f64,
+    pub median: f64,
+    pub stddev: f64,
+}
+
+impl Stats {
+    fn new(batch: u64, mut samples: Vec<f64>) -> Stats {
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let variance = samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (n - 1.0).max(1.0);
+        samples.sort_by(|a, b| a.total_cmp(b));
+        let mid = samples.len() / 2;
+        let median = if samples.len().is_multiple_of(2) {
+            (samples[mid - 1] + samples[mid]) / 2.0
+        } else {
+            samples[mid]
+        };
+        Stats {
+            iterations: batch * samples.len() as u64,
+            mean,
+            median,
+            stddev: variance.sqrt(),
+        }
+    }
+
+    // parse the marker line written by the benchmark
+    fn parse(line: &str) -> Option<Stats> {
+        let mut words = line.split_whitespace();
+        let batch = words.next()?.parse().ok()?;
+        let samples = words
+            .map(|w| w.parse().ok())
+            .collect::<Option<Vec<f64>>>()?;
+        if samples.is_empty() {
+            return None;
+        }
+        Some(Stats::new(batch, samples))
+    }
+}
+
+fn duration(ns: f64) -> String {
+    if ns < 1e3 {
+        format!("{:.2} ns", ns)
+    } else if ns < 1e6 {
+        format!("{:.2} µs", ns / 1e3)
+    } else if ns < 1e9 {
+        format!("{:.2} ms", ns / 1e6)
+    } else {
+        format!("{:.2} s", ns / 1e9)
+    }
+}
+
+fn rate(ns: f64) -> String {
+    let per_sec = 1e9 / ns;
+    if per_sec >= 1e9 {
+        format!("{:.2}G", per_sec / 1e9)
+    } else if per_sec >= 1e6 {
+        format!("{:.2}M", per_sec / 1e6)
+    } else if per_sec >= 1e3 {
+        format!("{:.2}K", per_sec / 1e3)
+    } else {
+        format!("{:.2}", per_sec)
+    }
+}
+
+// compile and time one snippet; any other output of the snippet passes through
+fn measure(
+    code: String,
+    file: Option<&Path>,
+    options: &SnippetOptions,
+    state: &mut State,
+) -> Result<Stats> {
+    let prog = Program::new(code, file, options, state)?;
+    if prog.line_map.is_none() {
+        bail!("--bench needs a snippet, not a program with main");
+    }
+    if !prog.compile(state)? {
+        process::exit(1);
+    }
+    let mut running = prog.start_captured(state, &[])?;
+    let stdout = BufReader::new(running.child.stdout.take().unwrap());
+    let mut stats = None;
+    for line in stdout.lines() {
+        let line = line.context("cannot read benchmark output")?;
+        match line.strip_prefix(MARKER) {
+            Some(rest) => stats = Stats::parse(rest),
+            None => println!("{}", line),
+        }
+    }
+    let status = running.wait()?;
+    if !status.success() {
+        process::exit(status.code().unwrap_or(-1));
+    }
+    stats.context("benchmark did not report its timings")
+}
+
+// each snippet is given as (name, code, file); with more than one,
+// they are compared against the first
+pub fn bench(
+    snippets: Vec<(String, String, Option<&Path>)>,
+    options: &SnippetOptions,
+    state: &mut State,
+) -> Result<()> {
+    let mut results = Vec::new();
+    for (name, code, file) in snippets {
+        let stats = measure(code, file, options, state)?;
+        results.push((name, stats));
+    }
+    let width = results
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let compare = results.len() > 1;
+    print!(
+        "{:w$}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+        "name",
+        "iterations",
+        "mean",
+        "median",
+        "stddev",
+        "iter/s",
+        w = width
+    );
+    println!("{}", if compare { "    relative" } else { "" });
+    let base = results[0].1.mean;
+    for (name, stats) in &results {
+        print!(
+            "{:w$}  {:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+            name,
+            stats.iterations,
+            duration(stats.mean),
+            duration(stats.median),
+            duration(stats.stddev),
+            rate(stats.mean),
+            w = width
+        );
+        if compare {
+            print!("  {:>9.2}x", stats.mean / base);
+        }
+        println!();
+    }
+    Ok(())
+}
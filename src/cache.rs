@@ -4,15 +4,20 @@ use crate::meta;
 use crate::{cache, crate_utils};
 use std::collections::HashMap;
 use std::env;
+use std::env::consts::EXE_SUFFIX;
 use std::fs;
+use std::hash::Hasher;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process;
 
 use crate_utils::is_unstable_toolchain;
 
+use crate::hash::Fnv;
 use crate::state::State;
+use crate::strutil::human_size;
 use anyhow::{bail, Context, Result};
+use semver::VersionReq;
 
 const STATIC_CACHE: &str = "static-cache";
 const DYNAMIC_CACHE: &str = "dy-cache";
@@ -74,6 +79,7 @@ pub fn cargo_build(release: bool) -> Result<Option<String>> {
     use std::io::BufReader;
 
     let mut c = process::Command::new("cargo");
+    c.current_dir(static_cache_dir()?);
     c.arg("build");
     if release {
         c.arg("--release");
@@ -132,7 +138,54 @@ fn remove_file_and_log(file_path: &Path) {
         println!("could not remove {}: {:?}", file_path.display(), e);
     }
 }
+// Runners in different terminals must not trip over each other. Anything
+// which changes a cache holds its lock exclusively, and compiles which use
+// it hold it shared. The lock is released when dropped.
+pub struct CacheLock(fs::File);
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+fn lock_cache(name: &str, exclusive: bool) -> Result<CacheLock> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(runner_directory()?.join(format!("{}.lock", name)))
+        .context("cannot open cache lock")?;
+    if exclusive {
+        file.lock()
+    } else {
+        file.lock_shared()
+    }
+    .context("cannot lock cache")?;
+    Ok(CacheLock(file))
+}
+
+pub fn lock_static_cache(exclusive: bool) -> Result<CacheLock> {
+    lock_cache(STATIC_CACHE, exclusive)
+}
+
+pub fn lock_dynamic_cache(exclusive: bool) -> Result<CacheLock> {
+    lock_cache(DYNAMIC_CACHE, exclusive)
+}
+
+// --gc holds this exclusively, and a runner holds it shared from finding
+// a cached expression until the program has started
+pub fn lock_bin(exclusive: bool) -> Result<CacheLock> {
+    lock_cache("bin", exclusive)
+}
+
 pub fn build_static_cache() -> Result<bool> {
+    let _lock = lock_static_cache(true)?;
+    rebuild_static_cache()
+}
+
+// the caller must hold the static cache lock
+fn rebuild_static_cache() -> Result<bool> {
     use crate::meta::*;
     let old = get_metadata().unwrap_or_else(|_| Meta::new());
     let mut m = Meta::new();
@@ -140,26 +193,139 @@ pub fn build_static_cache() -> Result<bool> {
         None => return Ok(false),
         Some(s) => m.release(s), // passop
     }?;
-    let deps = static_cache_dir()?
+    m.set_aliases(&crate_utils::dependencies(
+        &static_cache_dir()?.join("Cargo.toml"),
+    )?);
+    let deps = static_cache_deps(true)?;
+    // several versions of a package may be in the cache, so only
+    // versions which are no longer built are old
+    for q in &old.entries {
+        let still_built = m
+            .entries
+            .iter()
+            .any(|p| p.package == q.package && p.version == q.version);
+        if !still_built && m.entries.iter().any(|p| p.package == q.package) {
+            // get rid of the old version!
+            let old_rlib = &deps.join(&q.release_name);
+            let old_rmeta = &old_rlib.with_extension("rmeta");
+            remove_file_and_log(&old_rlib);
+            remove_file_and_log(&old_rmeta);
+        }
+    }
+    m.update(&static_cache_dir()?)?;
+    if !cargo(&["doc"])? {
+        eprintln!("warning: cannot build documentation for the static cache");
+    }
+    Ok(true)
+}
+
+fn static_cache_deps(release: bool) -> Result<PathBuf> {
+    Ok(static_cache_dir()?
         .join("target")
-        .join("release")
-        .join("deps");
-    for p in &m.entries {
-        if let Some(q) = old.entries.iter().find(|e| e.package == p.package) {
-            if q.version != p.version {
-                // get rid of the old version!
-                let old_rlib = &deps.join(&q.debug_name);
-                let old_rmeta = &old_rlib.with_extension("rmeta");
-                remove_file_and_log(&old_rlib);
-                remove_file_and_log(&old_rmeta);
+        .join(if release { "release" } else { "debug" })
+        .join("deps"))
+}
+
+// --debug snippets link against a debug build of the static cache,
+// which is only built if a library is missing, as after --add
+pub fn build_debug_cache() -> Result<()> {
+    // without a static cache there is nothing to build
+    if !meta::Meta::exists(&static_cache_dir()?) {
+        return Ok(());
+    }
+    let deps = static_cache_deps(false)?;
+    let current = |m: &meta::Meta| m.entries.iter().all(|e| deps.join(&e.debug_name).exists());
+    if current(&get_metadata()?) {
+        return Ok(());
+    }
+    // another runner may have built it while we waited
+    let _lock = lock_static_cache(true)?;
+    let mut m = get_metadata()?;
+    if current(&m) {
+        return Ok(());
+    }
+    eprintln!("building debug static cache");
+    match cargo_build(false)? {
+        None => bail!("cannot build the debug static cache"),
+        Some(s) => m.debug(s),
+    }?;
+    m.update(&static_cache_dir()?)
+}
+
+// files in deps belong to an artifact like 'libfoo-<hash>.rlib'
+// which is the same for 'foo-<hash>.d'
+fn artifact_key(file: &str) -> &str {
+    let stem = file.split('.').next().unwrap_or_default();
+    stem.strip_prefix("lib").unwrap_or(stem)
+}
+
+// remove everything in the static cache which is not a library in the
+// metadata, like old versions and builds with different features, keeping
+// the latest build of the static-cache program itself.
+pub fn cleanup_static_cache() -> Result<()> {
+    let _lock = lock_static_cache(true)?;
+    let m = get_metadata()?;
+    let mut total = 0;
+    for release in [true, false] {
+        let deps = static_cache_deps(release)?;
+        if !deps.is_dir() {
+            continue;
+        }
+        let names: Vec<&String> = m
+            .entries
+            .iter()
+            .map(|e| {
+                if release {
+                    &e.release_name
+                } else {
+                    &e.debug_name
+                }
+            })
+            .collect();
+        if !release && !names.iter().all(|name| deps.join(name).exists()) {
+            println!("debug build is not current: leaving {}", deps.display());
+            continue;
+        }
+        let mut keep: Vec<&str> = names.iter().map(|name| artifact_key(name)).collect();
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&deps)? {
+            let entry = entry?;
+            files.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.metadata()?,
+            ));
+        }
+        let program = files
+            .iter()
+            .filter(|(file, _)| file.starts_with("static_cache-"))
+            .max_by_key(|(_, meta)| meta.modified().ok())
+            .map(|(file, _)| artifact_key(file).to_string());
+        if let Some(program) = &program {
+            keep.push(program);
+        }
+        let (mut removed, mut saved) = (0, 0);
+        for (file, meta) in &files {
+            if !keep.contains(&artifact_key(file)) {
+                fs::remove_file(deps.join(file))
+                    .with_context(|| format!("cannot remove {}", file))?;
+                removed += 1;
+                saved += meta.len();
             }
         }
+        println!(
+            "{}: removed {} files, saving {}",
+            deps.display(),
+            removed,
+            human_size(saved)
+        );
+        total += saved;
     }
-    m.update(&static_cache_dir()?)?;
-    cargo(&["doc"])
+    println!("saved {} in all", human_size(total));
+    Ok(())
 }
 
 pub fn create_static_cache(crates: &[String]) -> Result<()> {
+    let _lock = lock_static_cache(true)?;
     let static_cache = static_cache_dir()?;
     let exists = static_cache.exists();
 
@@ -173,37 +339,254 @@ pub fn create_static_cache(crates: &[String]) -> Result<()> {
 
     home.push(STATIC_CACHE);
     env::set_current_dir(&home).context("cannot change to static cache directory")?;
-    // there are three forms possible
-    // a plain crate name - we assume latest version ('*')
-    // a name=vs - we'll ensure it gets quoted properly
-    // a local Cargo project
+    add_crates(&home, crates)
+}
+
+// there are four forms possible
+// a plain crate name - we assume latest version ('*')
+// a name=vs - we'll ensure it gets quoted properly
+// a name@vs - added alongside any other version
+// a local Cargo project
+// Must be in the static cache directory.
+fn add_crate(c: &str) -> Result<bool> {
+    if let Some((name, vs)) = c.split_once('@') {
+        add_crate_version(name, vs)
+    } else if c.contains("=") {
+        let c = c.replace("=", "@").to_string();
+        let mut args = vec!["add"];
+        if let Some(idx) = c.find('/') {
+            for feature in c[idx + 1..].split(',') {
+                args.push("--features");
+                args.push(feature);
+            }
+            args.push(&c[0..idx]);
+        } else {
+            args.push(c.as_str());
+        }
+        cargo(&args)
+    } else if let Some((_, path)) = maybe_cargo_dir(c)? {
+        // hello - this is a local Cargo project!
+        cargo(&["add", "--path", path.to_str().unwrap()])
+    } else {
+        // latest version of crate
+        cargo(&["add", c])
+    }
+}
+
+// add a particular version of a crate, alongside incompatible versions.
+// Cargo wants a different name for each of them, like 'rand_0_8'.
+fn add_crate_version(name: &str, vs: &str) -> Result<bool> {
+    let rename = format!(
+        "{}_{}",
+        name,
+        vs.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+            .trim_matches('_')
+    );
+    let package = format!("{}@{}", name, vs);
+    cargo(&["add", &package, "--rename", &rename])
+}
+
+// Cargo resolves one version of a package for each semver-compatible
+// range, which is a major version, or a minor version before 1.0
+type Range = (u64, Option<u64>);
+
+fn compatible_range(req: &VersionReq) -> Option<Range> {
+    let c = req.comparators.first()?;
+    Some(if c.major > 0 {
+        (c.major, None)
+    } else {
+        (0, c.minor)
+    })
+}
+
+// versions added alongside each other (or alongside the versions already
+// in the cache) must not be compatible, since they could not both be built
+fn check_versions(crates: &[String]) -> Result<()> {
+    let m = get_metadata().ok();
+    let mut seen: Vec<(&str, &str, Range)> = Vec::new();
     for c in crates {
-        if c.contains("=") {
-            let c = c.replace("=", "@").to_string();
-            let mut args = vec!["add"];
-            if let Some(idx) = c.find('/') {
-                for feature in c[idx + 1..].split(',') {
-                    args.push("--features");
-                    args.push(feature);
+        let Some((name, vs)) = c.split_once('@') else {
+            continue;
+        };
+        let req = meta::version_req(vs)?;
+        let Some(range) = compatible_range(&req) else {
+            continue;
+        };
+        if let Some((_, other, _)) = seen.iter().find(|(n, _, r)| *n == name && *r == range) {
+            bail!(
+                "{}@{} and {}@{} are semver-compatible, so the static cache cannot hold both",
+                name,
+                other,
+                name,
+                vs
+            );
+        }
+        let cached = m.iter().flat_map(|m| m.get_meta_entries(name));
+        for e in cached {
+            let version = VersionReq::parse(&format!("={}", e.version))?;
+            if !req.matches(&e.version) && compatible_range(&version) == Some(range) {
+                bail!(
+                    "{} {} is in the static cache, and is semver-compatible with {}@{}, so the cache cannot hold both",
+                    name,
+                    e.version,
+                    name,
+                    vs
+                );
+            }
+        }
+        seen.push((name, vs, range));
+    }
+    Ok(())
+}
+
+// the static cache manifest as it was, so that a failed --add can be undone
+struct Manifest {
+    toml: Vec<u8>,
+    lock: Option<Vec<u8>>,
+}
+
+impl Manifest {
+    fn save(dir: &Path) -> Result<Manifest> {
+        Ok(Manifest {
+            toml: fs::read(dir.join("Cargo.toml")).context("cannot read Cargo.toml")?,
+            lock: fs::read(dir.join("Cargo.lock")).ok(),
+        })
+    }
+
+    fn restore(&self, dir: &Path) -> Result<()> {
+        fs::write(dir.join("Cargo.toml"), &self.toml).context("cannot restore Cargo.toml")?;
+        let lock = dir.join("Cargo.lock");
+        match &self.lock {
+            Some(contents) => fs::write(&lock, contents),
+            None if lock.exists() => fs::remove_file(&lock),
+            None => Ok(()),
+        }
+        .context("cannot restore Cargo.lock")
+    }
+}
+
+// add the crates and rebuild; if anything goes wrong, the static cache
+// is left as it was. The caller must hold the static cache lock.
+fn add_crates(static_cache: &Path, crates: &[String]) -> Result<()> {
+    check_versions(crates)?;
+    let saved = Manifest::save(static_cache)?;
+    for c in crates {
+        if !add_crate(c).unwrap_or(false) {
+            saved.restore(static_cache)?;
+            bail!("cannot add {}, so the static cache is unchanged", c);
+        }
+    }
+    if !rebuild_static_cache()? {
+        let culprit = find_culprit(static_cache, crates, &saved)?;
+        saved.restore(static_cache)?;
+        bail!(
+            "adding {} broke the build, so the static cache is unchanged",
+            culprit
+        );
+    }
+    Ok(())
+}
+
+// which of the crates broke the build: add them one by one until it fails
+fn find_culprit(static_cache: &Path, crates: &[String], saved: &Manifest) -> Result<String> {
+    if crates.len() > 1 {
+        eprintln!("finding which crate broke the build");
+        saved.restore(static_cache)?;
+        for c in crates {
+            if !add_crate(c)? || !cargo(&["build", "--release"])? {
+                return Ok(c.clone());
+            }
+        }
+    }
+    Ok(crates.join(" "))
+}
+
+// take crates out of the static cache, along with their libraries and
+// documentation, and those of any dependencies which are no longer needed
+pub fn remove_crates(crates: &[String]) -> Result<()> {
+    let _lock = lock_static_cache(true)?;
+    let static_cache = static_cache_dir_check()?;
+    env::set_current_dir(&static_cache).context("static cache wasn't a directory?")?;
+    // a crate may be there under several names, like 'rand' and 'rand_0_8'
+    let deps = crate_utils::dependencies(&static_cache.join("Cargo.toml"))?;
+    let mut args = vec!["remove"];
+    for c in crates {
+        let len = args.len();
+        args.extend(
+            deps.iter()
+                .filter(|d| d.name == *c || d.package == *c)
+                .map(|d| d.name.as_str()),
+        );
+        if args.len() == len {
+            bail!("{} is not in the static cache", c);
+        }
+    }
+    let old = get_metadata().unwrap_or_else(|_| meta::Meta::new());
+    let saved = Manifest::save(&static_cache)?;
+    if !cargo(&args)? || !rebuild_static_cache()? {
+        saved.restore(&static_cache)?;
+        bail!(
+            "cannot remove {}, so the static cache is unchanged",
+            crates.join(" ")
+        );
+    }
+    // the rebuilt metadata only has what is still built
+    let m = get_metadata()?;
+    let doc = static_cache.join("target").join("doc");
+    for e in &old.entries {
+        let still_built = m.entries.iter().any(|p| {
+            p.package == e.package && p.version == e.version && p.crate_name == e.crate_name
+        });
+        if still_built {
+            continue;
+        }
+        println!("removing {} {}", e.package, e.version);
+        // if the crate has quite gone, so can any other builds of it
+        let gone = !m.entries.iter().any(|p| p.crate_name == e.crate_name);
+        let prefix = format!("{}-", e.crate_name);
+        for (release, name) in [(true, &e.release_name), (false, &e.debug_name)] {
+            let key = artifact_key(name);
+            remove_artifacts(&static_cache_deps(release)?, |k| {
+                k == key || (gone && k.starts_with(&prefix))
+            })?;
+        }
+        if gone {
+            for dir in [doc.join(&e.crate_name), doc.join("src").join(&e.crate_name)] {
+                if dir.is_dir() {
+                    fs::remove_dir_all(&dir)
+                        .with_context(|| format!("cannot remove {}", dir.display()))?;
                 }
-                args.push(&c[0..idx]);
-            } else {
-                args.push(c.as_str());
             }
-            cargo(&args)?;
-        } else if let Some((_, path)) = maybe_cargo_dir(&c)? {
-            // hello - this is a local Cargo project!
-            cargo(&["add", "--path", path.to_str().unwrap()])?;
-        } else {
-            // latest version of crate
-            cargo(&["add", c.as_str()])?;
         }
     }
+    Ok(())
+}
 
-    build_static_cache()?;
+fn remove_artifacts(deps: &Path, matches: impl Fn(&str) -> bool) -> Result<()> {
+    if !deps.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(deps)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy();
+        if matches(artifact_key(&name)) {
+            fs::remove_file(&path).with_context(|| format!("cannot remove {}", path.display()))?;
+        }
+    }
     Ok(())
 }
 
+pub fn add_crate_versions(name: &str, versions: &[String]) -> Result<()> {
+    let _lock = lock_static_cache(true)?;
+    let static_cache = static_cache_dir_check()?;
+    env::set_current_dir(&static_cache).context("static cache wasn't a directory?")?;
+    let crates: Vec<String> = versions
+        .iter()
+        .map(|vs| format!("{}@{}", name, vs))
+        .collect();
+    add_crates(&static_cache, &crates)
+}
+
 fn maybe_cargo_dir(name: &str) -> Result<Option<(String, PathBuf)>> {
     let path = Path::new(name);
     if !path.exists() || !path.is_dir() {
@@ -239,26 +622,66 @@ pub fn get_prelude() -> Result<String> {
 }
 
 pub fn get_cache(state: &State) -> Result<PathBuf> {
-    let mut home = runner_directory()?;
     if state.build_static {
-        home.push(STATIC_CACHE);
-        home.push("target");
-        home.push(if state.optimize { "release" } else { "debug" });
-        home.push("deps");
+        static_cache_deps(state.optimize)
     } else {
-        home.push(DYNAMIC_CACHE);
-    };
-    Ok(home)
+        Ok(runner_directory()?.join(DYNAMIC_CACHE))
+    }
 }
 
-// assume that `program` always exists, but `exe` may not
-pub fn compare_file_times(program: &Path, exe: &Path) -> Result<bool> {
-    let meta1 = program.metadata()?;
-    Ok(if let Ok(meta2) = exe.metadata() {
-        meta1.modified()? > meta2.modified()?
-    } else {
-        true
-    })
+// the file in a script's directory which says which script it is. No
+// program can have this name, since programs have no extension (or .exe)
+pub const SCRIPT_SOURCE: &str = "source.path";
+
+// each script gets its own directory in bin, named by a hash of its full
+// path, so scripts with the same name in different places don't collide
+pub fn script_directory(file: &Path) -> Result<PathBuf> {
+    let path = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    // the bytes of the path, since Path's Hash may change along with Rust
+    let mut h = Fnv::new();
+    h.write(path.as_os_str().as_encoded_bytes());
+    let dir = runner_directory()?
+        .join("bin")
+        .join(format!("{:016x}", h.finish()));
+    if !dir.is_dir() {
+        fs::create_dir_all(&dir).context("cannot create script directory")?;
+        fs::write(dir.join(SCRIPT_SOURCE), path.to_string_lossy().as_bytes())
+            .context("cannot write script source")?;
+    }
+    Ok(dir)
+}
+
+// which programs in bin belong to which script
+pub fn list_scripts() -> Result<()> {
+    let mut scripts = Vec::new();
+    for entry in fs::read_dir(runner_directory()?.join("bin"))? {
+        let dir = entry?.path();
+        if let Ok(source) = fs::read_to_string(dir.join(SCRIPT_SOURCE)) {
+            let mut programs = Vec::new();
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                let ext = path.extension().unwrap_or_default();
+                if ext == EXE_SUFFIX.trim_start_matches('.') {
+                    programs.push(path);
+                }
+            }
+            programs.sort();
+            scripts.push((source, programs));
+        }
+    }
+    scripts.sort();
+    for (source, programs) in scripts {
+        let missing = if Path::new(&source).exists() {
+            ""
+        } else {
+            " (missing)"
+        };
+        println!("{}{}", source, missing);
+        for p in programs {
+            println!("\t{}", p.display());
+        }
+    }
+    Ok(())
 }
 
 // Find scripts (and env.rs) on `RUNNER_PATH` if defined.
@@ -0,0 +1,152 @@
+// Running a snippet in several configurations, to see where it compiles
+// and whether it behaves differently: under each Rust edition, or
+// against different versions of a crate in the static cache.
+use crate::cache;
+use crate::meta::version_req;
+use crate::program::Program;
+use crate::state::{SnippetOptions, State};
+use anyhow::{Context, Result};
+use std::path::Path;
+
+const EDITIONS: &[&str] = &["2015", "2018", "2021", "2024"];
+
+// what a run did; None if it did not compile
+type Outcome = Option<(Option<i32>, String)>;
+
+fn run(
+    code: &str,
+    file: Option<&Path>,
+    options: &SnippetOptions,
+    state: &mut State,
+) -> Result<Outcome> {
+    let prog = Program::new(code.to_string(), file, options, state)?;
+    if !prog.compile(state)? {
+        return Ok(None);
+    }
+    prog.output(state).map(Some)
+}
+
+// print a table of the outcomes, where runs which behave the same are
+// labelled alike. Returns true if they all agree.
+fn report(title: &str, outcomes: &[(String, Outcome)]) -> bool {
+    let width = outcomes
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max(title.len());
+    let mut distinct: Vec<&(Option<i32>, String)> = Vec::new();
+    println!(
+        "{:w$} {:9} {:7} output",
+        title,
+        "compiles",
+        "status",
+        w = width
+    );
+    for (name, outcome) in outcomes {
+        match outcome {
+            Some(run) => {
+                let index = match distinct.iter().position(|d| *d == run) {
+                    Some(index) => index,
+                    None => {
+                        distinct.push(run);
+                        distinct.len() - 1
+                    }
+                };
+                let status = run.0.map_or("killed".to_string(), |c| c.to_string());
+                let label = (b'A' + index as u8) as char;
+                println!("{:w$} {:9} {:7} {}", name, "yes", status, label, w = width);
+            }
+            None => println!("{:w$} {:9} {:7} -", name, "no", "-", w = width),
+        }
+    }
+    if distinct.len() > 1 {
+        for (index, run) in distinct.iter().enumerate() {
+            println!("\n--- output {}", (b'A' + index as u8) as char);
+            print!("{}", run.1);
+        }
+    }
+    distinct.len() == 1 && outcomes.iter().all(|(_, o)| o.is_some())
+}
+
+// compile and run under every edition
+pub fn editions(
+    code: &str,
+    file: Option<&Path>,
+    options: &SnippetOptions,
+    state: &mut State,
+) -> Result<bool> {
+    let edition = state.edition.clone();
+    let mut outcomes = Vec::new();
+    for ed in EDITIONS {
+        state.edition = ed.to_string();
+        state.variant = format!("-edition{}", ed);
+        // the errors can be seen by running with -E
+        state.quiet = true;
+        let outcome = run(code, file, options, state);
+        state.quiet = false;
+        outcomes.push((ed.to_string(), outcome?));
+    }
+    state.edition = edition;
+    state.variant.clear();
+    Ok(report("edition", &outcomes))
+}
+
+// `spec` is like 'rand=0.7,0.8'. Versions not in the static cache are
+// added first; then the snippet is linked against each version in turn.
+pub fn versions(
+    spec: &str,
+    code: &str,
+    file: Option<&Path>,
+    options: &SnippetOptions,
+    state: &mut State,
+) -> Result<bool> {
+    let (name, versions) = spec
+        .split_once('=')
+        .context("expecting crate=version,version...")?;
+    let versions: Vec<String> = versions.split(',').map(|v| v.trim().to_string()).collect();
+    let reqs = versions
+        .iter()
+        .map(|v| version_req(v))
+        .collect::<Result<Vec<_>>>()?;
+
+    let m = cache::get_metadata()?;
+    let missing: Vec<String> = versions
+        .iter()
+        .zip(&reqs)
+        .filter(|(_, req)| m.get_meta_entry_matching(name, req).is_none())
+        .map(|(v, req)| {
+            if v.starts_with(|c: char| c.is_ascii_digit()) {
+                req.to_string()
+            } else {
+                v.clone()
+            }
+        })
+        .collect();
+    if !missing.is_empty() {
+        cache::add_crate_versions(name, &missing)?;
+    }
+
+    let m = cache::get_metadata()?;
+    state.build_static = true;
+    // the crate is linked even if the snippet does not `use` it
+    state.externs.push(name.to_string());
+    let mut outcomes = Vec::new();
+    for req in reqs {
+        let version = m
+            .get_meta_entry_matching(name, &req)
+            .with_context(|| format!("no version {} of {} in static cache", req, name))?
+            .version
+            .to_string();
+        // compile errors are shown as they happen, so say which version
+        eprintln!("{} {}", name, version);
+        state.versions.insert(name.to_string(), req);
+        // dots would be taken for an extension
+        state.variant = format!("-{}_{}", name, version.replace('.', "_"));
+        let outcome = run(code, file, options, state)?;
+        outcomes.push((version, outcome));
+    }
+    state.versions.remove(name);
+    state.variant.clear();
+    Ok(report(name, &outcomes))
+}
@@ -1,14 +1,20 @@
+use crate::bench;
 use crate::cache;
+use crate::config;
 use crate::crate_utils;
-use crate::state::State;
+use crate::expect::{self, Expectation};
+use crate::linemap::{Code, LineMap};
+use crate::state::{SnippetCrates, State};
 use anyhow::{bail, Context, Result};
 
 use crate::strutil::{split, word_after};
-use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::env;
 use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
 use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
 
 fn simplify_qualified_names(text: &str) -> String {
     let std = "std::";
@@ -28,6 +34,8 @@ fn simplify_qualified_names(text: &str) -> String {
 // handle two useful cases:
 // - compile a crate as a dynamic library, given a name and an output dir
 // - compile a program, given a program
+// If the program was generated from a snippet, then the line map is used
+// to make errors refer to the original files.
 pub fn compile_crate(
     state: &State,
     crate_name: &str,
@@ -35,6 +43,7 @@ pub fn compile_crate(
     output_program: Option<&Path>,
     mut extern_crates: Vec<String>,
     features: Vec<String>,
+    line_map: Option<&LineMap>,
 ) -> Result<bool> {
     let verbose = state.verbose;
     let simplify = state.simplify;
@@ -64,9 +73,13 @@ pub fn compile_crate(
     }
     if !state.build_static {
         // stripped-down dynamic link
-        builder
-            .args(&["-C", "prefer-dynamic"])
-            .args(&["-C", "debuginfo=0"]);
+        builder.args(&["-C", "prefer-dynamic"]);
+        if state.optimize {
+            builder.args(&["-C", "debuginfo=0"]);
+        }
+        if state.bench && state.optimize {
+            builder.arg("-O");
+        }
         if let Some(link) = &state.link {
             if verbose {
                 println!("linking against {}", link);
@@ -75,11 +88,31 @@ pub fn compile_crate(
         }
     } else {
         // static build
-        builder.arg(if state.optimize { "-O" } else { "-g" });
         if state.optimize {
             // TODO but stacktraces??
             // no point in carrying around all that baggage...
-            builder.args(&["-C", "debuginfo=0"]);
+            builder.arg("-O").args(&["-C", "debuginfo=0"]);
+        } else if !extern_crates.is_empty() {
+            // the debug build of the static cache is made when first needed
+            cache::build_debug_cache()?;
+        }
+    }
+    if debug {
+        builder.args(["-g", "-C", "debug-assertions", "-C", "overflow-checks"]);
+    }
+    // dynamic libraries are written to the dynamic cache, and
+    // executables are linked against a cache
+    let _lock = if state.build_static {
+        cache::lock_static_cache(false)?
+    } else {
+        cache::lock_dynamic_cache(!state.exe)?
+    };
+    if let Some(name) = &state.profile {
+        for (key, value) in config::profile(name)? {
+            if !state.build_static && config::STATIC_ONLY.contains(&key.as_str()) {
+                continue;
+            }
+            builder.arg("-C").arg(format!("{}={}", key, value));
         }
     }
     // implicitly linking against crates in the dynamic or static cache
@@ -94,6 +127,9 @@ pub fn compile_crate(
             .arg(&crate_utils::proper_crate_name(crate_name));
     } else {
         builder.arg("-o").arg(output_program.unwrap());
+        if state.test {
+            builder.arg("--test");
+        }
     }
     for c in cfg {
         builder.arg("--cfg").arg(&c);
@@ -108,7 +144,8 @@ pub fn compile_crate(
             .into_iter()
             .map(|c| {
                 Ok::<(String, String), String>((
-                    m.get_full_crate_name(&c, debug).ok_or_else(|| c.clone())?,
+                    m.get_full_crate_name(&c, state.versions.get(&c), debug)
+                        .ok_or_else(|| c.clone())?,
                     c,
                 ))
             })
@@ -147,15 +184,21 @@ pub fn compile_crate(
         builder.arg("--extern").arg(&ext);
     }
     builder.arg(crate_path);
-    if simplify {
+    if simplify || line_map.is_some() || state.quiet {
         if std::io::stderr().is_terminal() {
             builder.args(&["--color", "always"]);
         }
         let output = builder.output().context("can't run rustc")?;
         let status = output.status.success();
-        if !status {
-            let err = String::from_utf8_lossy(&output.stderr);
-            eprintln!("{}", simplify_qualified_names(&err));
+        if !status && !state.quiet {
+            let mut err = String::from_utf8_lossy(&output.stderr).to_string();
+            if let Some(line_map) = line_map {
+                err = line_map.map_diagnostics(crate_path, &err);
+            }
+            if simplify {
+                err = simplify_qualified_names(&err);
+            }
+            eprintln!("{}", err);
         }
         Ok(status)
     } else {
@@ -163,101 +206,252 @@ pub fn compile_crate(
     }
 }
 
+// a command for running a compiled program, which
+// must be able to see the dynamic cache
+pub fn program_command(state: &State, program: &Path) -> Result<process::Command> {
+    let ch = cache::get_cache(state)?;
+    let mut builder = process::Command::new(program);
+    if !state.optimize && env::var_os("RUST_BACKTRACE").is_none() {
+        builder.env("RUST_BACKTRACE", "1");
+    }
+    if !state.build_static {
+        // must make the dynamic cache visible to the program!
+        if cfg!(windows) {
+            // Windows resolves DLL references on the PATH
+            let path = env::var("PATH")?;
+            let new_path = format!("{};{}", path, ch.display());
+            builder.env("PATH", new_path);
+        } else {
+            // whereas POSIX requires LD_LIBRARY_PATH
+            builder.env(
+                "LD_LIBRARY_PATH",
+                format!("{}:{}", crate_utils::rustup_lib()?, ch.display()),
+            );
+        }
+    }
+    Ok(builder)
+}
+
+// what the generated program does with the snippet body
+#[derive(Clone, Copy)]
+pub enum Wrapper {
+    // a program which reports any error returned by the body
+    Main { debug_errors: bool },
+    // a test harness, where the body (if any) becomes an implicit test
+    Test,
+    // a program which times the body
+    Bench,
+}
+
+impl Wrapper {
+    // so that differently wrapped builds of a file don't overwrite each other
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Wrapper::Main { .. } => "",
+            Wrapper::Test => "-test",
+            Wrapper::Bench => "-bench",
+        }
+    }
+}
+
+// The prelude comes as (path, contents) pairs, so that the returned line map
+// can also point at the runner prelude and env.rs
 pub fn massage_snippet(
     code: String,
-    prelude: String,
-    extern_crates: Vec<String>,
-    wild_crates: Vec<String>,
-    macro_crates: HashSet<String>,
+    source: Option<&Path>,
+    prelude: Vec<(PathBuf, String)>,
+    crates: &SnippetCrates,
     body_prelude: String,
-) -> Result<(String, Vec<String>)> {
-    use crate::strutil::{after, word_after};
+    wrapper: Wrapper,
+) -> Result<(String, Vec<String>, LineMap, Vec<Expectation>)> {
+    use crate::snippet::{self, split_snippet, Place};
 
-    fn indent_line(line: &str) -> String {
-        format!("    {}\n", line)
-    }
-
-    let mut prefix = prelude;
-    let mut crate_begin = String::new();
-    let mut body = String::new();
-    let mut deduced_externs = Vec::new();
+    let mut prefix = Code::new();
+    let mut crate_begin = Code::new();
+    let mut body = Code::new();
+    let mut items = Code::new();
 
-    body += &body_prelude;
-    if extern_crates.len() > 0 {
+    for (path, text) in prelude {
+        prefix.push_from(Some(&Arc::from(path)), 1, "", 0, &text);
+    }
+    if !body_prelude.is_empty() {
+        body.push_from(None, 1, "    ", 0, &body_prelude);
+    }
+    if !crates.externs.is_empty() {
         let aliases = cache::get_aliases()?;
-        for c in &extern_crates {
-            prefix += &if let Some(aliased) = aliases.get(c) {
+        for c in &crates.externs {
+            prefix.push(&if let Some(aliased) = aliases.get(c) {
                 format!("extern crate {} as {};\n", aliased, c)
             } else {
-                let mac = if macro_crates.contains(c) {
+                let mac = if crates.macros.contains(c) {
                     "#[macro_use] "
                 } else {
                     ""
                 };
                 format!("{}extern crate {};\n", mac, c)
-            };
+            });
         }
-        for c in wild_crates {
-            prefix += &format!("use {}::*;\n", c);
+        for c in &crates.wild {
+            prefix.push(&format!("use {}::*;\n", c));
         }
     }
-    let mut lines = code.lines();
-    let mut first = true;
-    for line in lines.by_ref() {
-        let line = line.trim_start();
-        if first {
-            // files may start with #! shebang or comment...
-            if line.starts_with("#!/") || line.starts_with("//") {
-                continue;
+    // Crate imports, use and macro definitions should go at the top, and
+    // crate-level attributes must go right at the beginning.
+    // Any other items go after `main`; only statements go into `run`.
+    // Parts keep their original columns, and body parts are indented
+    let source: Option<Arc<Path>> = source.map(Arc::from);
+    // statements annotated with `// => value` are checked when run. The
+    // annotations are blanked out, and expressions given a semicolon if
+    // that lets the snippet parse; if nothing does, rustc will complain
+    let mut annotations = if matches!(wrapper, Wrapper::Main { .. }) {
+        expect::annotations(&code)
+    } else {
+        BTreeMap::new()
+    };
+    let code = if annotations.is_empty() {
+        code
+    } else {
+        let terminated = expect::blank(&code, &mut annotations, true);
+        if snippet::parses(&terminated) {
+            terminated
+        } else {
+            let blanked = expect::blank(&code, &mut annotations, false);
+            if snippet::parses(&blanked) {
+                blanked
+            } else {
+                annotations.clear();
+                code
             }
-            first = false;
         }
-        // crate import, use should go at the top.
-        // Particularly need to force crate-level attributes to the top
-        // These must not be in the `run` function we're generating
-        if let Some(rest) = after(line, "#[macro_use") {
-            if let Some(crate_name) = word_after(rest, "extern crate ") {
-                deduced_externs.push(crate_name);
+    };
+    let (mut expectations, mut checked) = (Vec::new(), Vec::new());
+    let parsed = split_snippet(&code);
+    let last_body = parsed.parts.iter().rposition(|p| p.place == Place::Body);
+    let location = |line| match &source {
+        Some(path) => format!("{}:{}", path.display(), line),
+        None => format!("line {}", line),
+    };
+    for (i, part) in parsed.parts.iter().enumerate() {
+        let (out, indent) = match part.place {
+            Place::CrateBegin => (&mut crate_begin, ""),
+            Place::Prefix => (&mut prefix, ""),
+            Place::Item => (&mut items, ""),
+            Place::Body => (&mut body, "    "),
+        };
+        // a benchmark must not let the optimizer discard the final value
+        let black_box = matches!(wrapper, Wrapper::Bench) && part.value && Some(i) == last_body;
+        if black_box {
+            out.push("    std::hint::black_box(");
+        }
+        let last_line = part.line + part.text.lines().count().max(1) - 1;
+        // an annotation belongs to the last statement ending before it
+        let annotated = annotations.get(&last_line).filter(|a| {
+            let end = match part.text.rfind('\n') {
+                Some(eol) => part.text[eol + 1..].chars().count(),
+                None => part.column + part.text.chars().count(),
+            };
+            end <= a.column + a.terminated as usize
+                && parsed
+                    .parts
+                    .get(i + 1)
+                    .is_none_or(|next| next.line > last_line)
+        });
+        match annotated {
+            Some(a) if part.place == Place::Body => {
+                let value = part.value || a.terminated;
+                let text = if a.terminated {
+                    part.text.strip_suffix(';').unwrap_or(part.text)
+                } else {
+                    part.text
+                };
+                out.push(&expect::before(expectations.len(), value));
+                out.push_from(source.as_ref(), part.line, indent, part.column, text);
+                out.push(&expect::after(value));
+                expectations.push(Expectation {
+                    location: location(last_line),
+                    expected: a.expected.clone(),
+                });
+                checked.push(last_line);
             }
-            prefix += line;
-            prefix.push('\n');
-        } else if line.starts_with("extern ") || line.starts_with("use ") {
-            extract_externs(line, &mut deduced_externs);
-            prefix += line;
-            prefix.push('\n');
-        } else if line.starts_with("#![") {
-            // inner attributes really need to be at the top of the file
-            crate_begin += line;
-            crate_begin.push('\n');
-        } else if line.len() > 0 {
-            body += &indent_line(line);
-            break;
+            _ => out.push_from(source.as_ref(), part.line, indent, part.column, part.text),
+        }
+        if black_box {
+            out.push("    );");
         }
     }
-    // and indent the rest!
-    body.extend(lines.map(indent_line));
+    // anything else would silently never be checked
+    if let Some(line) = annotations.keys().find(|l| !checked.contains(l)) {
+        bail!(
+            "{}: `// =>` must follow a statement outside any block",
+            location(*line)
+        );
+    }
+    let mut deduced_externs = parsed.externs;
 
-    deduced_externs.extend(extern_crates);
+    deduced_externs.extend(crates.externs.iter().cloned());
     deduced_externs.sort();
     deduced_externs.dedup();
 
-    let massaged_code = format!(
-        "{}
-{}
-
-fn run(args: Vec<String>) -> std::result::Result<(),Box<dyn std::error::Error+Sync+Send>> {{
-{}    Ok(())
-}}
-fn main() {{
-    if let Err(e) = run(std::env::args().collect()) {{
-        println!(\"error: {{:?}}\",e);
-    }}
-}}
-",
-        crate_begin, prefix, body
-    );
+    let mut massaged_code = crate_begin;
+    massaged_code.append(prefix);
+    // a test harness has no use for `run` unless there are statements
+    let has_body = parsed.parts.iter().any(|p| p.place == Place::Body);
+    if has_body || !matches!(wrapper, Wrapper::Test) {
+        // a benchmark calls `run` many times, so it borrows the arguments
+        massaged_code.push(if matches!(wrapper, Wrapper::Bench) {
+            "
+fn run(args: &[String]) -> std::result::Result<(),Box<dyn std::error::Error+Sync+Send>> {
+"
+        } else {
+            "
+fn run(args: Vec<String>) -> std::result::Result<(),Box<dyn std::error::Error+Sync+Send>> {
+"
+        });
+        massaged_code.append(body);
+        massaged_code.push("    Ok(())\n}\n");
+    }
+    massaged_code.push(match wrapper {
+        // failures go to stderr with a non-zero exit status, showing the error chain
+        Wrapper::Main { debug_errors: true } => {
+            "fn main() {
+    if let Err(e) = run(std::env::args().collect()) {
+        eprintln!(\"error: {:?}\",e);
+        std::process::exit(1);
+    }
+}
+"
+        }
+        Wrapper::Main {
+            debug_errors: false,
+        } => {
+            "fn main() {
+    if let Err(e) = run(std::env::args().collect()) {
+        eprintln!(\"error: {}\",e);
+        let mut source = std::error::Error::source(&*e);
+        while let Some(e) = source {
+            eprintln!(\"  caused by: {}\",e);
+            source = e.source();
+        }
+        std::process::exit(1);
+    }
+}
+"
+        }
+        // the harness owns the command line, so the snippet gets no arguments
+        Wrapper::Test if has_body => {
+            "#[test]
+fn snippet() -> std::result::Result<(),Box<dyn std::error::Error+Sync+Send>> {
+    run(Vec::new())
+}
+"
+        }
+        Wrapper::Test => "",
+        Wrapper::Bench => bench::MAIN,
+    });
+    massaged_code.append(items);
 
-    Ok((massaged_code, deduced_externs))
+    let line_map = massaged_code.line_map();
+    Ok((massaged_code.text, deduced_externs, line_map, expectations))
 }
 
 pub fn extract_externs(line: &str, deduced_externs: &mut Vec<String>) -> bool {
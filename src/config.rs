@@ -0,0 +1,102 @@
+// runner's own settings, kept in config.toml in runner's directory.
+// Codegen profiles live in [profile.NAME] sections, like
+//
+//   [profile.fast]
+//   opt-level = 3
+//   target-cpu = "native"
+//
+// and are translated into rustc -C flags. The built-in profiles
+// can be changed there, and new ones added.
+use crate::cache::runner_directory;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use toml::value::{Table, Value};
+
+const CONFIG: &str = "config.toml";
+
+// the codegen settings a profile may have
+const SETTINGS: &[&str] = &[
+    "opt-level",
+    "target-cpu",
+    "lto",
+    "codegen-units",
+    "panic",
+    "strip",
+    "overflow-checks",
+    "debug-assertions",
+    "debuginfo",
+];
+
+const PROFILES: &str = r#"
+[profile.fast]
+opt-level = 3
+target-cpu = "native"
+codegen-units = 1
+
+[profile.small]
+opt-level = "z"
+codegen-units = 1
+panic = "abort"
+strip = "symbols"
+
+[profile.dev]
+opt-level = 0
+debuginfo = 2
+debug-assertions = true
+overflow-checks = true
+"#;
+
+pub fn read_config() -> Result<Table> {
+    let file = runner_directory()?.join(CONFIG);
+    if !file.exists() {
+        return Ok(Table::new());
+    }
+    let text = fs::read_to_string(&file).context("cannot read runner config")?;
+    match text.parse::<Value>() {
+        Ok(Value::Table(t)) => Ok(t),
+        _ => bail!("{}: cannot parse config", file.display()),
+    }
+}
+
+fn profile_table(config: &Table, name: &str) -> Option<Table> {
+    config
+        .get("profile")
+        .and_then(|p| p.get(name))
+        .and_then(|p| p.as_table())
+        .cloned()
+}
+
+// these only make sense for a static build, since the standard
+// library shared object is built with unwinding and without LTO
+pub const STATIC_ONLY: &[&str] = &["lto", "panic"];
+
+// the -C flags for a profile, as (setting, value) pairs. Settings in
+// the config override those of a built-in profile with the same name.
+pub fn profile(name: &str) -> Result<Vec<(String, String)>> {
+    let builtin = PROFILES.parse::<Value>().unwrap();
+    let builtin = builtin.as_table().unwrap();
+    let mut settings = profile_table(builtin, name).unwrap_or_default();
+    let found = !settings.is_empty();
+    let config = read_config()?;
+    match profile_table(&config, name) {
+        Some(t) => settings.extend(t),
+        None if !found => bail!("no such profile {:?}", name),
+        None => {}
+    }
+    let mut flags = Vec::new();
+    for (key, value) in settings {
+        if !SETTINGS.contains(&key.as_str()) {
+            bail!("profile {:?}: unknown setting {:?}", name, key);
+        }
+        let value = match value {
+            Value::String(s) => s,
+            Value::Integer(i) => i.to_string(),
+            // as in Cargo, `strip = true` strips symbols
+            Value::Boolean(b) if key == "strip" => (if b { "symbols" } else { "none" }).to_string(),
+            Value::Boolean(b) => (if b { "yes" } else { "no" }).to_string(),
+            _ => bail!("profile {:?}: bad value for {:?}", name, key),
+        };
+        flags.push((key, value));
+    }
+    Ok(flags)
+}
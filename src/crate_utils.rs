@@ -28,6 +28,23 @@ pub fn rustup_lib() -> Result<&'static str> {
         .expect("rustup lib path should be initialized"))
 }
 
+static RUSTC_VERSION_VALUE: OnceLock<String> = OnceLock::new();
+
+pub fn rustc_version() -> Result<&'static str> {
+    if RUSTC_VERSION_VALUE.get().is_none() {
+        let output = std::process::Command::new("rustc")
+            .arg("--version")
+            .output()
+            .context("cannot query rustc version")?;
+        if !output.status.success() {
+            bail!("rustc --version failed");
+        }
+        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        let _ = RUSTC_VERSION_VALUE.set(value);
+    }
+    Ok(RUSTC_VERSION_VALUE.get().unwrap())
+}
+
 pub fn is_unstable_toolchain() -> Result<bool> {
     Ok(rustup_lib()?.contains("nightly"))
 }
@@ -93,3 +110,40 @@ pub fn crate_info(cargo_toml: &Path) -> Result<CrateInfo> {
     .to_string();
     Ok(CrateInfo { name, edition })
 }
+
+// a dependency of a Cargo project. With a renamed dependency, like
+// `rand08 = { package = "rand", version = "0.8" }`, `name` is the new name
+pub struct Dependency {
+    pub name: String,
+    pub package: String,
+    pub version: Option<String>,
+    pub path: Option<PathBuf>,
+}
+
+pub fn dependencies(cargo_toml: &Path) -> Result<Vec<Dependency>> {
+    let body = fs::read_to_string(cargo_toml).context("cannot read Cargo.toml")?;
+    let toml = body
+        .parse::<toml::Value>()
+        .with_context(|| format!("{}: cannot parse Cargo.toml", cargo_toml.display()))?;
+    // paths are relative to the project
+    let dir = cargo_toml.parent().unwrap_or(Path::new("."));
+    let mut res = Vec::new();
+    if let Some(deps) = toml.get("dependencies").and_then(|d| d.as_table()) {
+        for (name, dep) in deps {
+            let field = |key: &str| dep.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+            res.push(Dependency {
+                name: name.clone(),
+                package: field("package").unwrap_or_else(|| name.clone()),
+                version: dep
+                    .as_str()
+                    .map(|s| s.to_string())
+                    .or_else(|| field("version")),
+                path: field("path").map(|p| {
+                    let path = dir.join(p);
+                    path.canonicalize().unwrap_or(path)
+                }),
+            });
+        }
+    }
+    Ok(res)
+}
@@ -0,0 +1,277 @@
+// Inline expectations in snippets, like `debug!(v.len()); // => 5` or
+// `2 + 2 // => 4`. Annotated statements are bracketed with markers on
+// stdout, so that what they print can be checked against the annotation.
+// An annotated expression prints its Debug value.
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+const MARKER: &str = "\u{1}runner-expect";
+const END_MARKER: &str = "\u{1}runner-expect-end";
+
+const ANNOTATION: &str = "// =>";
+
+pub struct Annotation {
+    pub expected: String,
+    // where the comment starts, as a byte offset and a column in chars
+    offset: usize,
+    pub column: usize,
+    // a semicolon was put in front of the comment, to end an expression
+    pub terminated: bool,
+}
+
+// where an annotated statement came from, and what it should print
+#[derive(Clone)]
+pub struct Expectation {
+    pub location: String,
+    pub expected: String,
+}
+
+// where a `//` comment starts, if it is not inside a literal or another
+// comment, from the bytes following `start`
+fn line_comment(code: &str, start: usize) -> Option<usize> {
+    let bytes = code.as_bytes();
+    let mut i = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'/') => return Some(i),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                // block comments nest
+                let mut depth = 0;
+                while i < bytes.len() {
+                    if bytes[i..].starts_with(b"/*") {
+                        depth += 1;
+                        i += 2;
+                    } else if bytes[i..].starts_with(b"*/") {
+                        depth -= 1;
+                        i += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else {
+                        i += 1;
+                    }
+                }
+                continue;
+            }
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            b'\'' => {
+                // a char literal, or else a lifetime or label
+                let mut chars = code[i + 1..].chars();
+                match (chars.next(), chars.next()) {
+                    (Some('\\'), _) => {
+                        i += 3;
+                        while i < bytes.len() && bytes[i] != b'\'' {
+                            i += 1;
+                        }
+                    }
+                    (Some(c), Some('\'')) => i += c.len_utf8() + 1,
+                    _ => {}
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                let end = code[i..]
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .map_or(code.len(), |n| i + n);
+                // raw strings like r#"..."#, which may contain quotes
+                if ["r", "br", "cr"].contains(&&code[i..end]) {
+                    let hashes = code[end..].len() - code[end..].trim_start_matches('#').len();
+                    if code[end + hashes..].starts_with('"') {
+                        let close = format!("\"{}", "#".repeat(hashes));
+                        let from = end + hashes + 1;
+                        i = code[from..].find(&close).map_or(code.len(), |n| from + n)
+                            + close.len();
+                        continue;
+                    }
+                }
+                i = end;
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+// find the `// =>` comments which follow code (by 1-based line number).
+// Comment markers inside strings and other comments don't count.
+pub fn annotations(code: &str) -> BTreeMap<usize, Annotation> {
+    let mut annotations = BTreeMap::new();
+    let mut start = 0;
+    while let Some(offset) = line_comment(code, start) {
+        let eol = code[offset..].find('\n').map_or(code.len(), |n| offset + n);
+        let comment = &code[offset..eol];
+        let line_start = code[..offset].rfind('\n').map_or(0, |n| n + 1);
+        let after_code = !code[line_start..offset].trim().is_empty();
+        match comment.strip_prefix(ANNOTATION) {
+            Some(expected) if after_code => {
+                annotations.insert(
+                    code[..offset].matches('\n').count() + 1,
+                    Annotation {
+                        expected: expected.trim().to_string(),
+                        offset,
+                        column: code[line_start..offset].chars().count(),
+                        terminated: false,
+                    },
+                );
+            }
+            _ => {}
+        }
+        start = eol;
+    }
+    annotations
+}
+
+// the code with the annotations blanked out, keeping the line lengths.
+// With `terminate`, expressions are given a semicolon, so that they can
+// be followed by other statements.
+pub fn blank(code: &str, annotations: &mut BTreeMap<usize, Annotation>, terminate: bool) -> String {
+    let mut res = String::new();
+    let mut last = 0;
+    for a in annotations.values_mut() {
+        let eol = code[a.offset..]
+            .find('\n')
+            .map_or(code.len(), |n| a.offset + n);
+        let before = &code[last..a.offset];
+        a.terminated = terminate && !before.trim_end().ends_with(';');
+        res.push_str(before);
+        res.push(if a.terminated { ';' } else { ' ' });
+        res.extend(std::iter::repeat_n(
+            ' ',
+            code[a.offset..eol].chars().count() - 1,
+        ));
+        last = eol;
+    }
+    res.push_str(&code[last..]);
+    res
+}
+
+// generated lines which go before and after the annotated statement
+pub fn before(index: usize, value: bool) -> String {
+    let mut res = format!("    println!(\"{} {}\");\n", MARKER.escape_default(), index);
+    if value {
+        res += "    println!(\"{:?}\", &(\n";
+    }
+    res
+}
+
+pub fn after(value: bool) -> String {
+    let mut res = String::new();
+    if value {
+        res += "    ));\n";
+    }
+    res += &format!("    println!(\"{}\");\n", END_MARKER.escape_default());
+    res
+}
+
+// what was printed matches if it is the expected text, or if it is a single
+// line like `debug!` produces, 'x.len() = 5', and the value matches
+fn matches(printed: &str, expected: &str) -> bool {
+    let printed = printed.trim_end();
+    printed == expected
+        || (!printed.contains('\n')
+            && printed
+                .split_once(" = ")
+                .is_some_and(|(_, value)| value.trim() == expected))
+}
+
+// copy the program's output without the markers, reporting any statement
+// which did not print what it should on stderr. Returns how many did not.
+pub fn check(
+    expectations: &[Expectation],
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<usize> {
+    let mut current: Option<(usize, String)> = None;
+    let mut checked = vec![false; expectations.len()];
+    let mut failed = 0;
+    let mut report = |index: usize, printed: &str| {
+        let e = &expectations[index];
+        checked[index] = true;
+        if !matches(printed, &e.expected) {
+            let mut err = io::stderr();
+            writeln!(err, "{}: output does not match", e.location)?;
+            writeln!(err, "- {}", e.expected)?;
+            for line in printed.trim_end().lines() {
+                writeln!(err, "+ {}", line)?;
+            }
+            failed += 1;
+        }
+        Ok::<(), io::Error>(())
+    };
+    for line in input.lines() {
+        let line = line?;
+        if line == END_MARKER {
+            if let Some((index, printed)) = current.take() {
+                report(index, &printed)?;
+            }
+        } else if let Some(index) = line
+            .strip_prefix(MARKER)
+            .and_then(|rest| rest.trim().parse::<usize>().ok())
+        {
+            current = Some((index, String::new()));
+        } else {
+            if let Some((_, printed)) = &mut current {
+                printed.push_str(&line);
+                printed.push('\n');
+            }
+            writeln!(output, "{}", line)?;
+        }
+    }
+    // statements which never finished (or never ran) have failed
+    if let Some((index, printed)) = current.take() {
+        report(index, &printed)?;
+    }
+    for (index, e) in expectations.iter().enumerate() {
+        if !checked[index] {
+            eprintln!("{}: statement did not run", e.location);
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(code: &str) -> Vec<(usize, String)> {
+        annotations(code)
+            .into_iter()
+            .map(|(line, a)| (line, a.expected))
+            .collect()
+    }
+
+    #[test]
+    fn finds_annotations_after_code() {
+        let code = "let v = vec![1];\nv.len() // => 1\n// => not after code\n";
+        assert_eq!(lines(code), [(2, "1".to_string())]);
+    }
+
+    #[test]
+    fn skips_literals_and_comments() {
+        let code = r####"let s = "a // => b";
+let r = r#"" // => c"#;
+let c = '"'; // => 1
+/* x // => 2 */ let d = 'x';
+fn f<'a>(s: &'a str) -> &'a str { s } // => 3
+"####;
+        assert_eq!(lines(code), [(3, "1".to_string()), (5, "3".to_string())]);
+    }
+
+    #[test]
+    fn blanking_keeps_lines_and_columns() {
+        let code = "2 + 2 // => 4\nlet x = 1; // => ()\nx\n";
+        let mut found = annotations(code);
+        let blanked = blank(code, &mut found, true);
+        assert_eq!(blanked, "2 + 2 ;      \nlet x = 1;         \nx\n");
+        assert!(found[&1].terminated);
+        assert!(!found[&2].terminated);
+        assert_eq!(found[&1].column, 6);
+    }
+}
@@ -0,0 +1,266 @@
+// --gc: cleaning out runner's bin directory. Programs of scripts which
+// still exist are kept; those of scripts which have gone are removed, as
+// are cached expressions (and files from the old flat layout) which have
+// not been used for a while, oldest first if the directory is too large.
+// Settings come from the [gc] section of config.toml:
+//
+//   [gc]
+//   older-than = "30d"
+//   max-size = "500M"
+//   auto = true        # collect in the background now and then (default false)
+//   interval = "1d"
+use crate::cache::{lock_bin, runner_directory, SCRIPT_SOURCE};
+use crate::config::read_config;
+use crate::strutil::human_size;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+const DAY: u64 = 24 * 60 * 60;
+
+// when the last background collection happened
+const STAMP: &str = "gc-stamp";
+
+pub struct Settings {
+    older_than: Duration,
+    max_size: Option<u64>,
+    auto: bool,
+    interval: Duration,
+}
+
+// like '30d', '12h' or '2w'; a plain number is days
+fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let num: u64 = num
+        .parse()
+        .with_context(|| format!("bad duration {:?}", s))?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "" | "d" => DAY,
+        "w" => 7 * DAY,
+        _ => bail!("bad duration {:?}: use s, m, h, d or w", s),
+    };
+    Ok(Duration::from_secs(num * secs))
+}
+
+// like '500M' or '2G'; a plain number is bytes
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let num: u64 = num.parse().with_context(|| format!("bad size {:?}", s))?;
+    let scale = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => bail!("bad size {:?}: use K, M or G", s),
+    };
+    Ok(num * scale)
+}
+
+impl Settings {
+    // the config, overridden by the command line
+    pub fn new(older_than: &str, max_size: &str) -> Result<Settings> {
+        let config = read_config()?;
+        let gc = config.get("gc").and_then(|g| g.as_table());
+        let get = |key: &str| gc.and_then(|g| g.get(key));
+        let get_str = |key: &str, arg: &str| -> Option<String> {
+            if !arg.is_empty() {
+                Some(arg.to_string())
+            } else {
+                get(key).and_then(|v| v.as_str()).map(|s| s.to_string())
+            }
+        };
+        let older_than = get_str("older-than", older_than).unwrap_or("30d".into());
+        let interval = get_str("interval", "").unwrap_or("1d".into());
+        Ok(Settings {
+            older_than: parse_duration(&older_than)?,
+            max_size: get_str("max-size", max_size)
+                .map(|s| parse_size(&s))
+                .transpose()?,
+            auto: get("auto").and_then(|v| v.as_bool()).unwrap_or(false),
+            interval: parse_duration(&interval)?,
+        })
+    }
+}
+
+// something which can be removed: a script's directory, or the
+// files which make up a cached expression
+struct Item {
+    name: PathBuf,
+    paths: Vec<PathBuf>,
+    size: u64,
+    modified: SystemTime,
+    // the script has gone, so it goes regardless
+    orphan: bool,
+}
+
+impl Item {
+    fn new(name: PathBuf, orphan: bool) -> Item {
+        Item {
+            name,
+            paths: Vec::new(),
+            size: 0,
+            modified: SystemTime::UNIX_EPOCH,
+            orphan,
+        }
+    }
+
+    fn add(&mut self, path: PathBuf) -> Result<()> {
+        let (size, modified) = size_and_time(&path)?;
+        self.size += size;
+        self.modified = self.modified.max(modified);
+        self.paths.push(path);
+        Ok(())
+    }
+}
+
+// total size and latest modification of a file or directory
+fn size_and_time(path: &Path) -> Result<(u64, SystemTime)> {
+    let meta = fs::symlink_metadata(path)?;
+    let mut res = (meta.len(), meta.modified()?);
+    if meta.is_dir() {
+        res.0 = 0;
+        for entry in fs::read_dir(path)? {
+            let (size, time) = size_and_time(&entry?.path())?;
+            res.0 += size;
+            res.1 = res.1.max(time);
+        }
+    }
+    Ok(res)
+}
+
+// files which belong together, like 'tmp.rs' and 'tmp', share a stem
+fn group_files(dir: &Path, items: &mut Vec<Item>) -> Result<()> {
+    let mut groups: BTreeMap<String, Item> = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            continue;
+        }
+        let name = path.file_name().unwrap().to_string_lossy();
+        let stem = name.split('.').next().unwrap_or_default().to_string();
+        groups
+            .entry(stem.clone())
+            .or_insert_with(|| Item::new(dir.join(stem), false))
+            .add(path)?;
+    }
+    items.extend(groups.into_values());
+    Ok(())
+}
+
+fn items(bin: &Path) -> Result<Vec<Item>> {
+    let mut items = Vec::new();
+    for entry in fs::read_dir(bin)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        if path.ends_with("expr") {
+            group_files(&path, &mut items)?;
+        } else if let Ok(source) = fs::read_to_string(path.join(SCRIPT_SOURCE)) {
+            if !Path::new(&source).exists() {
+                let mut item = Item::new(path.clone(), true);
+                item.add(path)?;
+                items.push(item);
+            }
+        }
+    }
+    // files from before scripts had their own directories
+    group_files(bin, &mut items)?;
+    Ok(items)
+}
+
+fn remove(item: &Item) -> Result<()> {
+    for path in &item.paths {
+        if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        }
+        .with_context(|| format!("cannot remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+pub fn collect(settings: &Settings) -> Result<()> {
+    // wait for runners which are about to run a cached expression
+    let _lock = lock_bin(true)?;
+    let bin = runner_directory()?.join("bin");
+    let items = items(&bin)?;
+    let now = SystemTime::now();
+    let old = |item: &Item| {
+        now.duration_since(item.modified)
+            .is_ok_and(|age| age > settings.older_than)
+    };
+    let (mut removed, mut kept): (Vec<Item>, Vec<Item>) =
+        items.into_iter().partition(|item| item.orphan || old(item));
+    // if still too big, remove what was used longest ago
+    if let Some(max_size) = settings.max_size {
+        let (total, _) = size_and_time(&bin)?;
+        let mut size = total - removed.iter().map(|item| item.size).sum::<u64>();
+        kept.sort_by_key(|item| item.modified);
+        for item in kept {
+            if size <= max_size {
+                break;
+            }
+            size -= item.size;
+            removed.push(item);
+        }
+    }
+    let mut reclaimed = 0;
+    for item in &removed {
+        remove(item)?;
+        let why = if item.orphan {
+            "script has gone"
+        } else {
+            "unused"
+        };
+        println!(
+            "removed {} ({}, {})",
+            item.name.display(),
+            human_size(item.size),
+            why
+        );
+        reclaimed += item.size;
+    }
+    println!(
+        "reclaimed {} in {} items",
+        human_size(reclaimed),
+        removed.len()
+    );
+    Ok(())
+}
+
+// start a collection in the background, if it is time for one
+pub fn maybe_collect() -> Result<()> {
+    let settings = Settings::new("", "")?;
+    if !settings.auto {
+        return Ok(());
+    }
+    let stamp = runner_directory()?.join(STAMP);
+    let due = fs::metadata(&stamp)
+        .and_then(|m| m.modified())
+        .map_or(true, |time| {
+            SystemTime::now()
+                .duration_since(time)
+                .is_ok_and(|age| age > settings.interval)
+        });
+    if due {
+        fs::write(&stamp, "")?;
+        Command::new(env::current_exe()?)
+            .arg("--gc")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,376 @@
+// generated programs are assembled from the prelude, env.rs and the
+// snippet itself, so line numbers in rustc errors (and panics) refer to
+// the generated file. We remember where each generated line came from,
+// so that these locations can be mapped back.
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Clone)]
+struct Origin {
+    path: Arc<Path>,
+    // 1-based line in the original file
+    line: usize,
+    // number of columns added in front of the original text
+    shift: usize,
+}
+
+// generated code, with the origin of each line
+#[derive(Default)]
+pub struct Code {
+    pub text: String,
+    origins: Vec<Option<Origin>>,
+}
+
+impl Code {
+    pub fn new() -> Code {
+        Code::default()
+    }
+
+    fn ensure_newline(&mut self) {
+        if !self.text.is_empty() && !self.text.ends_with('\n') {
+            self.text.push('\n');
+        }
+    }
+
+    // lines made up by runner
+    pub fn push(&mut self, text: &str) {
+        self.ensure_newline();
+        self.text.push_str(text);
+        self.origins.extend(text.lines().map(|_| None));
+    }
+
+    // lines from `path`, starting at `line`; `indent` goes in front of each
+    // line and `column` spaces in front of the first, so columns stay put.
+    pub fn push_from(
+        &mut self,
+        path: Option<&Arc<Path>>,
+        line: usize,
+        indent: &str,
+        column: usize,
+        text: &str,
+    ) {
+        self.ensure_newline();
+        for (i, l) in text.lines().enumerate() {
+            self.text.push_str(indent);
+            if i == 0 {
+                self.text.extend(std::iter::repeat_n(' ', column));
+            }
+            self.text.push_str(l);
+            self.text.push('\n');
+            self.origins.push(path.map(|path| Origin {
+                path: path.clone(),
+                line: line + i,
+                shift: indent.len(),
+            }));
+        }
+    }
+
+    pub fn append(&mut self, other: Code) {
+        self.ensure_newline();
+        self.text.push_str(&other.text);
+        self.origins.extend(other.origins);
+    }
+
+    pub fn line_map(&self) -> LineMap {
+        LineMap {
+            origins: self.origins.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LineMap {
+    origins: Vec<Option<Origin>>,
+}
+
+// byte range of the line number in a rustc gutter like ' 23 | ',
+// skipping any ANSI colour escapes
+fn gutter_number(line: &str) -> Option<(usize, usize)> {
+    let bytes = line.as_bytes();
+    let (mut i, mut digits) = (0, None);
+    while i < bytes.len() {
+        let b = bytes[i];
+        if b == 0x1b {
+            i += 1;
+            if i < bytes.len() && bytes[i] == b'[' {
+                i += 1;
+                while i < bytes.len() && !(0x40..=0x7e).contains(&bytes[i]) {
+                    i += 1;
+                }
+            }
+        } else if b.is_ascii_digit() {
+            if digits.is_some() {
+                return None;
+            }
+            let start = i;
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            digits = Some((start, i));
+            continue;
+        } else if b == b'|' {
+            return digits;
+        } else if b != b' ' {
+            return None;
+        }
+        i += 1;
+    }
+    None
+}
+
+// parse 'LINE:COL' at the start of `s`, returning the rest
+fn line_col(s: &str) -> Option<(usize, usize, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit())?;
+    let line = s[..end].parse().ok()?;
+    let s = s[end..].strip_prefix(':')?;
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let col = s[..end].parse().ok()?;
+    Some((line, col, &s[end..]))
+}
+
+impl LineMap {
+    fn origin(&self, line: usize) -> Option<&Origin> {
+        self.origins.get(line.checked_sub(1)?)?.as_ref()
+    }
+
+    // replace every 'GENERATED:LINE:COL' with the original location
+    pub fn map_locations(&self, generated: &Path, line: &str) -> String {
+        let gen = format!("{}:", generated.display());
+        let mut res = String::new();
+        let mut s = line;
+        while let Some(pos) = s.find(&gen) {
+            res.push_str(&s[..pos]);
+            let rest = &s[pos + gen.len()..];
+            match line_col(rest).and_then(|(l, c, rest)| Some((self.origin(l)?, c, rest))) {
+                Some((o, col, rest)) => {
+                    let col = if col > o.shift { col - o.shift } else { col };
+                    res.push_str(&format!("{}:{}:{}", o.path.display(), o.line, col));
+                    s = rest;
+                }
+                None => {
+                    res.push_str(&gen);
+                    s = rest;
+                }
+            }
+        }
+        res.push_str(s);
+        res
+    }
+
+    // rewrite rustc diagnostics so that spans name the original files.
+    // The source lines under a header may come from another file, like the
+    // prelude, and then a `:::` line says which.
+    pub fn map_diagnostics(&self, generated: &Path, text: &str) -> String {
+        let gen = format!("{}:", generated.display());
+        let mut res = String::new();
+        let mut in_generated = false;
+        // the original file of the current header, and the indent of its arrow
+        let mut current: Option<Arc<Path>> = None;
+        let mut indent = String::new();
+        for line in text.lines() {
+            if let Some(arrow) = line.find("--> ").or_else(|| line.find("::: ")) {
+                in_generated = line.contains(&gen);
+                indent = line[..arrow].to_string();
+                current = line
+                    .find(&gen)
+                    .and_then(|pos| line_col(&line[pos + gen.len()..]))
+                    .and_then(|(l, _, _)| self.origin(l))
+                    .map(|o| o.path.clone());
+            }
+            let mapped = match gutter_number(line) {
+                Some((start, end)) if in_generated => {
+                    let old = &line[start..end];
+                    match old.parse().ok().and_then(|l| self.origin(l)) {
+                        Some(o) => {
+                            if current.as_ref() != Some(&o.path) {
+                                // the column where the source text starts
+                                let bar = line[end..].find('|').map_or(line.len(), |i| end + i + 1);
+                                let source = line[bar..].strip_prefix(' ').unwrap_or(&line[bar..]);
+                                let col = (source.len() - source.trim_start().len() + 1)
+                                    .saturating_sub(o.shift)
+                                    .max(1);
+                                res.push_str(&format!(
+                                    "{}::: {}:{}:{}\n",
+                                    indent,
+                                    o.path.display(),
+                                    o.line,
+                                    col
+                                ));
+                                current = Some(o.path.clone());
+                            }
+                            format!(
+                                "{}{:>w$}{}",
+                                &line[..start],
+                                o.line,
+                                &line[end..],
+                                w = old.len()
+                            )
+                        }
+                        None => line.to_string(),
+                    }
+                }
+                _ => self.map_locations(generated, line),
+            };
+            res.push_str(&mapped);
+            res.push('\n');
+        }
+        res
+    }
+
+    // copy a program's output line by line, so that panic locations and
+    // backtrace frames refer to the original files
+    pub fn copy_mapped(
+        &self,
+        generated: &Path,
+        input: impl BufRead,
+        mut output: impl Write,
+    ) -> io::Result<()> {
+        for line in input.split(b'\n') {
+            let line = line?;
+            match std::str::from_utf8(&line) {
+                Ok(line) => writeln!(output, "{}", self.map_locations(generated, line))?,
+                Err(_) => {
+                    output.write_all(&line)?;
+                    output.write_all(b"\n")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // lines 1-2 are made up, 3-4 come from the prelude, and 5-6 are
+    // snippet lines 1-2 indented by four spaces
+    fn code() -> Code {
+        let mut code = Code::new();
+        code.push("fn run() {\n// generated\n");
+        code.push_from(
+            Some(&Arc::from(Path::new("prelude"))),
+            1,
+            "",
+            0,
+            "use std::fmt;\nuse std::io;\n",
+        );
+        code.push_from(
+            Some(&Arc::from(Path::new("snip.rs"))),
+            1,
+            "    ",
+            0,
+            "let x = 1;\nlet y = ;\n",
+        );
+        code
+    }
+
+    #[test]
+    fn line_col_parses() {
+        assert_eq!(line_col("12:5: error"), Some((12, 5, ": error")));
+        assert_eq!(line_col("12:5"), Some((12, 5, "")));
+        assert_eq!(line_col("12 error"), None);
+        assert_eq!(line_col("x:5"), None);
+    }
+
+    #[test]
+    fn gutter_numbers() {
+        assert_eq!(gutter_number(" 23 |     let y = ;"), Some((1, 3)));
+        assert_eq!(gutter_number("\x1b[1m\x1b[94m6\x1b[0m | x"), Some((9, 10)));
+        assert_eq!(gutter_number("    |"), None);
+        assert_eq!(gutter_number("error: 2 | 3"), None);
+        assert_eq!(gutter_number("1 2 |"), None);
+    }
+
+    #[test]
+    fn locations() {
+        let map = code().line_map();
+        let gen = Path::new("/bin/gen.rs");
+        assert_eq!(
+            map.map_locations(gen, "panicked at /bin/gen.rs:6:13:"),
+            "panicked at snip.rs:2:9:"
+        );
+        assert_eq!(
+            map.map_locations(gen, "at /bin/gen.rs:4:1 and /bin/gen.rs:1:1"),
+            "at prelude:2:1 and /bin/gen.rs:1:1"
+        );
+        assert_eq!(
+            map.map_locations(gen, "elsewhere.rs:6:1"),
+            "elsewhere.rs:6:1"
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let map = code().line_map();
+        let gen = Path::new("/bin/gen.rs");
+        let rustc = "\
+error: expected expression, found `;`
+ --> /bin/gen.rs:6:13
+  |
+6 |     let y = ;
+  |             ^ expected expression
+
+warning: unused import
+  --> /other.rs:6:1
+   |
+ 6 | use std::fmt;
+";
+        let expected = "\
+error: expected expression, found `;`
+ --> snip.rs:2:9
+  |
+2 |     let y = ;
+  |             ^ expected expression
+
+warning: unused import
+  --> /other.rs:6:1
+   |
+ 6 | use std::fmt;
+";
+        assert_eq!(map.map_diagnostics(gen, rustc), expected);
+    }
+
+    #[test]
+    fn lines_from_other_files_are_labelled() {
+        let map = code().line_map();
+        let gen = Path::new("/bin/gen.rs");
+        let rustc = "\
+error[E0252]: the name `fmt` is defined multiple times
+ --> /bin/gen.rs:5:9
+  |
+3 | use std::fmt;
+  |     -------- previous import of the module `fmt` here
+4 | use std::io;
+5 |     let x = 1;
+  |         ^ `fmt` reimported here
+";
+        let expected = "\
+error[E0252]: the name `fmt` is defined multiple times
+ --> snip.rs:1:5
+  |
+ ::: prelude:1:1
+1 | use std::fmt;
+  |     -------- previous import of the module `fmt` here
+2 | use std::io;
+ ::: snip.rs:1:1
+1 |     let x = 1;
+  |         ^ `fmt` reimported here
+";
+        assert_eq!(map.map_diagnostics(gen, rustc), expected);
+    }
+
+    #[test]
+    fn panics_are_mapped() {
+        let map = code().line_map();
+        let output = b"thread 'main' panicked at /bin/gen.rs:5:9:\nboom\n";
+        let mut mapped = Vec::new();
+        map.copy_mapped(Path::new("/bin/gen.rs"), &output[..], &mut mapped)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(mapped).unwrap(),
+            "thread 'main' panicked at snip.rs:1:5:\nboom\n"
+        );
+    }
+}
@@ -5,28 +5,39 @@ use lapp;
 use shlex;
 
 use anyhow::{bail, Context, Result};
-use std::collections::HashSet;
 use std::env;
 use std::env::consts::EXE_SUFFIX;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process;
 
+mod batch;
+mod bench;
 mod cache;
 mod cargo_lock;
+mod compare;
 mod compile;
+mod config;
 mod crate_utils;
+mod expect;
+mod gc;
+mod hash;
+mod linemap;
+mod markdown;
 mod meta;
 mod platform;
+mod program;
+mod repl;
+mod snippet;
 mod state;
 mod strutil;
+mod watch;
 
-use crate::cache::{compare_file_times, lookup_file_path};
-use crate::compile::extract_externs;
-use compile::{compile_crate, massage_snippet};
-use crate_utils::rustup_lib;
+use crate::cache::lookup_file_path;
+use compile::compile_crate;
 use platform::{edit, open};
-use state::State;
+use program::Program;
+use state::{SnippetOptions, State};
 
 const VERSION: &str = "0.7.0";
 
@@ -37,6 +48,7 @@ Compile and run small Rust snippets
   -e, --expression evaluate an expression
   -i, --iterator iterate over an expression
   -n, --lines evaluate expression over stdin; the var 'line' is defined
+  --repl interactive loop; input accumulates, and values of expressions are shown
   -x, --extern... (string) add an extern crate to the snippet
   -X, --wild... (string) like -x but implies wildcard import
   -M, --macro... (string) like -x but implies macro import
@@ -46,17 +58,35 @@ Compile and run small Rust snippets
   -o, --output (path default cargo) change the default output dir for compilation
   -r, --run  don't compile, only re-run
   -R, --rerun only recompile if snippet has fresher timestamp
+  -w, --watch recompile and rerun when the script or anything it uses changes
+  -t, --test build with rustc --test and run the tests (arguments are test filters)
+  -b, --bench time a snippet or expression; given more, compare them
+  --markdown run the Rust code blocks in a Markdown file, like doc tests
+  --weave put the output of each Rust block in a Markdown file after it
+  --check with --weave, only fail if the output is out of date
+  --batch run each .rs file in a directory, comparing with its .expected file
+  --bless with --batch, write the .expected files from the actual output
+  -g, --debug compile with debug info, debug assertions and overflow checks
+  --profile (default '') codegen profile: fast, small, dev, or one in runner's config.toml
   -S, --no-simplify by default, attempt to simplify rustc error messages
+  --error-debug use Debug instead of Display for errors returned by snippets
   -E, --edition (default '2024') Rust edition
+  --edition-matrix compile and run under each edition, and compare the results
+  --versions (default '') compare results with versions of a crate, like rand=0.7,0.8
 
   Cache Management:
   --add  (string...) add new crates to the cache
+  --remove (string...) remove crates from the cache
   --update update all, or a specific package given as argument
   --edit  edit the static cache Cargo.toml
   --build rebuild the static cache
   --cleanup clean out stale rlibs from cache
   --crates current crates and their versions in cache
   --doc  display documentation (any argument will be specific crate name)
+  --gc remove programs of scripts which have gone, and unused expressions
+  --older-than (default '') with --gc, what counts as unused, like 30d (the default)
+  --max-size (default '') with --gc, also remove the oldest until bin is this size, like 500M
+  --scripts list the scripts with programs in the bin directory
   --edit-prelude edit the default prelude for snippets
   --alias (string...) crate aliases in form alias=crate_name (used with -x)
 
@@ -115,15 +145,15 @@ fn main() -> Result<()> {
 
     let env_prelude = if let Some(env) = lookup_file_path("env.rs", Some(&file)) {
         let (contents, _) = read_file_with_arg_comment(&mut args, &env)?;
-        Some(contents)
+        Some((env, contents))
     } else {
         None
     };
 
-    let mut prelude = cache::get_prelude()?;
-    if let Some(env_prelude) = env_prelude {
-        prelude.push_str(&env_prelude);
-    }
+    // the prelude is kept with its origin, so errors can point at it
+    let prelude_contents = cache::get_prelude()?;
+    let mut prelude = vec![(cache::runner_directory()?.join("prelude"), prelude_contents)];
+    prelude.extend(env_prelude);
     let b = |p| args.get_bool(p);
 
     let exe_suffix = if EXE_SUFFIX.len() > 0 {
@@ -148,6 +178,20 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    if b("gc") {
+        let settings =
+            gc::Settings::new(&args.get_string("older-than"), &args.get_string("max-size"))?;
+        gc::collect(&settings)?;
+        return Ok(());
+    }
+    // best effort: a failure here should not stop the snippet
+    let _ = gc::maybe_collect();
+
+    if b("scripts") {
+        cache::list_scripts()?;
+        return Ok(());
+    }
+
     if b("edit-prelude") {
         let rdir = cache::runner_directory()?.join("prelude");
         edit(&rdir)?;
@@ -166,6 +210,12 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    let crates = args.get_strings("remove");
+    if crates.len() > 0 {
+        cache::remove_crates(&crates)?;
+        return Ok(());
+    }
+
     // operations on the static cache
     let (edit_toml, build, doc, update, cleanup, crates) = (
         b("edit"),
@@ -184,6 +234,7 @@ fn main() -> Result<()> {
             if build {
                 cache::build_static_cache()?;
             } else {
+                let _lock = cache::lock_static_cache(true)?;
                 if let Ok(package) = maybe_argument {
                     cache::cargo(&["update", "--package", &package])?;
                 } else {
@@ -200,7 +251,7 @@ fn main() -> Result<()> {
             let docs = static_cache.join(&format!("target/doc/{}/index.html", the_crate));
             open(&docs)?;
         } else if cleanup {
-            cache::cargo(&["clean"])?;
+            cache::cleanup_static_cache()?;
         } else if crates {
             let mut m = cache::get_metadata()?;
             let mut crates = Vec::new();
@@ -217,14 +268,27 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    let first_arg = args.get_string("program");
+    let optimized = !b("debug");
+
+    if b("batch") {
+        if !batch::batch(&file, b("bless"))? {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if b("repl") {
+        let state = State::exe(b("static"), optimized, &args)?;
+        let mut repl = repl::Repl::new(state, SnippetOptions::new(&args, prelude))?;
+        return repl.run();
+    }
 
-    let optimized = true; // all builds are now optimized
+    let first_arg = args.get_string("program");
 
     // Dynamically linking crates (experimental!)
     let (print_path, compile) = (b("crate-path"), b("compile"));
     if print_path || compile {
-        let mut state = State::dll(optimized, &args);
+        let mut state = State::dll(optimized, &args)?;
         // plain-jane name is a crate name!
         if crate_utils::plain_name(&first_arg) {
             // but is it one of Ours? Then we definitely know what the
@@ -262,6 +326,7 @@ fn main() -> Result<()> {
                             .split_whitespace()
                             .map(|s| s.to_string())
                             .collect(),
+                        None,
                     )?;
                 }
                 return Ok(());
@@ -306,6 +371,7 @@ fn main() -> Result<()> {
                 None,
                 Vec::new(),
                 Vec::new(),
+                None,
             )?;
             return Ok(());
         } else {
@@ -315,16 +381,59 @@ fn main() -> Result<()> {
     }
 
     let static_state = b("static");
-    let mut state = State::exe(static_state, optimized, &args);
+    let mut state = State::exe(static_state, optimized, &args)?;
 
     // we'll pass rest of arguments to program
     let program_args = args.get_strings("args");
 
+    if b("markdown") || b("weave") {
+        // doc tests link against the static cache
+        state.build_static = true;
+        let options = SnippetOptions::new(&args, prelude);
+        let ok = if b("weave") {
+            markdown::weave(&file, &options, &mut state, b("check"))?
+        } else {
+            markdown::run(&file, &options, &mut state)?
+        };
+        if !ok {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if b("bench") {
+        if b("test") {
+            bail!("--bench and --test make no sense together");
+        }
+        // all the arguments are benchmarks
+        let options = SnippetOptions::new(&args, prelude);
+        let mut snippets = Vec::new();
+        if b("expression") {
+            for expr in Some(first_arg).into_iter().chain(program_args) {
+                snippets.push((expr.clone(), cache::quote(expr), None));
+            }
+        } else {
+            let contents = program_contents.context("no .rs file")?;
+            snippets.push((first_arg, contents, Some(file.clone())));
+            for arg in program_args {
+                let path = lookup_file_path(&arg, None).context("source file does not exist")?;
+                let contents = fs::read_to_string(&path)
+                    .with_context(|| format!("cannot read {}", path.display()))?;
+                snippets.push((arg, contents, Some(path)));
+            }
+        }
+        let snippets = snippets
+            .iter()
+            .map(|(name, code, file)| (name.clone(), code.clone(), file.as_deref()))
+            .collect();
+        return bench::bench(snippets, &options, &mut state);
+    }
+
     let mut just_run = b("run");
     let mut expression = true;
     let display = b("display");
     use cache::quote;
-    let mut code = if b("expression") {
+    let code = if b("expression") {
         // Evaluating an expression: just debug print it out.
         if display {
             format!("println!(\"{{}}\",{});", quote(first_arg))
@@ -373,71 +482,39 @@ fn main() -> Result<()> {
         program_contents.context("no .rs file")?
     };
 
-    // ALL executables go into the Runner bin directory...
-    let mut bin = cache::runner_directory()?.join("bin");
-    let mut externs = Vec::new();
-
-    // proper Rust programs are accepted (this is a bit rough)
-    let proper = code.find("fn main").is_some();
-    let (rust_file, program) = if !proper {
-        // otherwise we must create a proper program from the snippet
-        // and write this as a file in the Runner bin directory...
-        let mut extern_crates = args.get_strings("extern");
-        let wild_crates = args.get_strings("wild");
-        let macro_crates = args.get_strings("macro");
-        if wild_crates.len() > 0 {
-            extern_crates.extend(wild_crates.iter().cloned());
-        }
-        if macro_crates.len() > 0 {
-            extern_crates.extend(macro_crates.iter().cloned());
-        }
-        let macro_crates: HashSet<_> = macro_crates.into_iter().collect();
-
-        let mut extra = args.get_string("prepend");
-        if !extra.is_empty() {
-            extra.push(';');
+    let options = SnippetOptions::new(&args, prelude);
+    let source = if expression {
+        None
+    } else {
+        Some(file.as_path())
+    };
+    if b("edition-matrix") {
+        if !compare::editions(&code, source, &options, &mut state)? {
+            process::exit(1);
         }
-        let maybe_prelude = if b("no-prelude") { "".into() } else { prelude };
-
-        let (massaged_code, deduced_externs) = massage_snippet(
-            code,
-            maybe_prelude,
-            extern_crates,
-            wild_crates,
-            macro_crates,
-            extra,
-        )?;
-        code = massaged_code;
-        externs = deduced_externs;
-        if !expression {
-            bin.push(file.file_name().unwrap());
-            bin.set_extension("rs");
-        } else {
-            // we make up a name...
-            bin.push("tmp.rs");
+        return Ok(());
+    }
+    let versions = args.get_string("versions");
+    if !versions.is_empty() {
+        if !compare::versions(&versions, &code, source, &options, &mut state)? {
+            process::exit(1);
         }
-        fs::write(&bin, &code).context("cannot write code")?;
-        let program = bin.with_extension(exe_suffix);
-        if !expression && b("rerun") {
-            just_run = !compare_file_times(&file, &program)?;
+        return Ok(());
+    }
+    if b("watch") {
+        if expression {
+            bail!("--watch needs a script file");
         }
-        (bin, program)
+        return watch::watch(&file, options, &mut state, &program_args);
+    }
+    let prog = Program::new(code, source, &options, &mut state)?;
+    let (rust_file, program) = (&prog.rust_file, &prog.program);
+    let fingerprint = if !expression && prog.line_map.is_some() && b("rerun") {
+        let fingerprint = prog.fingerprint(&file, &state)?;
+        just_run = prog.is_current(&fingerprint);
+        Some(fingerprint)
     } else {
-        let mut static_externs = 0;
-        // we are given a proper Rust source file, and deduce the crates needed for
-        // static linking from the source
-        for line in code.lines() {
-            if extract_externs(line, &mut externs) {
-                static_externs += 1;
-            }
-        }
-        if static_externs > 0 {
-            state.build_static = true;
-        }
-        // the 'proper' case - use the file name part
-        bin.push(file.file_name().unwrap());
-        let program = bin.with_extension(exe_suffix);
-        (file, program)
+        None
     };
 
     if just_run {
@@ -445,9 +522,12 @@ fn main() -> Result<()> {
             bail!("program {:?} does not exist", program);
         }
     } else {
-        if !compile_crate(&state, "", &rust_file, Some(&program), externs, Vec::new())? {
+        if !prog.compile(&state)? {
             process::exit(1);
         }
+        if let Some(fingerprint) = fingerprint {
+            prog.save_fingerprint(&fingerprint)?;
+        }
         if verbose {
             println!("compiled {:?} successfully", rust_file);
         }
@@ -473,32 +553,12 @@ fn main() -> Result<()> {
         };
         let here = home.join(file_name).with_extension(exe_suffix);
         println!("Copying {} to {}", program.display(), here.display());
-        fs::copy(&program, &here).context("cannot copy program")?;
+        fs::copy(program, &here).context("cannot copy program")?;
         return Ok(());
     }
 
     // Finally run the compiled program
-    let ch = cache::get_cache(&state)?;
-    let mut builder = process::Command::new(&program);
-    if !state.build_static {
-        // must make the dynamic cache visible to the program!
-        if cfg!(windows) {
-            // Windows resolves DLL references on the PATH
-            let path = env::var("PATH")?;
-            let new_path = format!("{};{}", path, ch.display());
-            builder.env("PATH", new_path);
-        } else {
-            // whereas POSIX requires LD_LIBRARY_PATH
-            builder.env(
-                "LD_LIBRARY_PATH",
-                format!("{}:{}", rustup_lib()?, ch.display()),
-            );
-        }
-    }
-    let status = builder
-        .args(&program_args)
-        .status()
-        .with_context(|| format!("can't run program {:?}", program))?;
+    let status = prog.start(&state, &program_args)?.wait()?;
 
     if !status.success() {
         process::exit(status.code().unwrap_or(-1));
@@ -0,0 +1,405 @@
+// Running the Rust code blocks in Markdown files, much as rustdoc runs
+// doc tests. Blocks are padded with blank lines so that their line numbers
+// are the line numbers in the Markdown file, which is named as their source.
+use crate::cache;
+use crate::compile::{extract_externs, massage_snippet, program_command, Wrapper};
+use crate::expect;
+use crate::linemap::Code;
+use crate::program::{exe_suffix, Program};
+use crate::snippet;
+use crate::state::{SnippetOptions, State};
+use anyhow::{Context, Result};
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Default)]
+struct Attributes {
+    ignore: bool,
+    no_run: bool,
+    should_panic: bool,
+    compile_fail: bool,
+    edition: Option<String>,
+}
+
+pub struct Block {
+    // line of the opening fence
+    pub line: usize,
+    // line of the closing fence (or the last line)
+    pub end: usize,
+    code: String,
+    attributes: Attributes,
+}
+
+// the info string of a fence, if this is a Rust block. Like rustdoc, we
+// accept blocks which have only attributes, but unlike rustdoc an untagged
+// block is not Rust, since it is usually a shell session in a README.
+fn rust_attributes(info: &str) -> Option<Attributes> {
+    let mut attributes = Attributes::default();
+    for token in info.split(|c: char| c == ',' || c.is_whitespace()) {
+        match token {
+            "" | "rust" => {}
+            "ignore" => attributes.ignore = true,
+            "no_run" => attributes.no_run = true,
+            "should_panic" => attributes.should_panic = true,
+            "compile_fail" => attributes.compile_fail = true,
+            _ => match token.strip_prefix("edition") {
+                Some(edition) => attributes.edition = Some(edition.to_string()),
+                None => return None,
+            },
+        }
+    }
+    if info.trim().is_empty() {
+        None
+    } else {
+        Some(attributes)
+    }
+}
+
+// lines starting with '# ' are hidden in the docs, but are part of the code
+fn unhide(line: &str) -> &str {
+    let trimmed = line.trim_start();
+    if trimmed == "#" {
+        ""
+    } else if let Some(rest) = trimmed.strip_prefix("# ") {
+        rest
+    } else if trimmed.starts_with("##") {
+        &trimmed[1..]
+    } else {
+        line
+    }
+}
+
+pub fn extract_blocks(text: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let trimmed = line.trim_start();
+        let ch = match trimmed.chars().next() {
+            Some(c @ '`') | Some(c @ '~') => c,
+            _ => continue,
+        };
+        let info = trimmed.trim_start_matches(ch);
+        // a block is closed by a fence at least as long as the one opening
+        // it, so woven output can contain shorter fences
+        let fence = &trimmed[..trimmed.len() - info.len()];
+        if fence.len() < 3 {
+            continue;
+        }
+        let attributes = rust_attributes(info);
+        // the code starts on the next line, so pad with a blank line for
+        // every line up to and including the fence
+        let mut code = "\n".repeat(i + 1);
+        let mut end = i;
+        for (j, line) in lines.by_ref() {
+            end = j;
+            let line_trimmed = line.trim();
+            if line_trimmed.starts_with(fence) && line_trimmed.trim_start_matches(ch).is_empty() {
+                break;
+            }
+            code += unhide(line);
+            code.push('\n');
+        }
+        if let Some(attributes) = attributes {
+            blocks.push(Block {
+                line: i + 1,
+                end: end + 1,
+                code,
+                attributes,
+            });
+        }
+    }
+    blocks
+}
+
+// make a program from the block; `fn main` means it is used as is
+fn block_program(md: &Path, block: &Block, options: &SnippetOptions) -> Result<Program> {
+    let name = format!(
+        "{}-md{}.rs",
+        md.file_stem().unwrap().to_string_lossy(),
+        block.line
+    );
+    let rust_file = cache::script_directory(md)?.join(name);
+    let (code, externs, line_map, expectations) = if snippet::is_program(&block.code) {
+        let mut externs = Vec::new();
+        for line in block.code.lines() {
+            extract_externs(line, &mut externs);
+        }
+        let mut code = Code::new();
+        code.push_from(Some(&Arc::from(md)), 1, "", 0, &block.code);
+        let line_map = code.line_map();
+        (code.text, externs, line_map, Vec::new())
+    } else {
+        massage_snippet(
+            block.code.clone(),
+            Some(md),
+            options.prelude.clone(),
+            &options.crates,
+            options.body_prelude.clone(),
+            Wrapper::Main {
+                debug_errors: false,
+            },
+        )?
+    };
+    fs::write(&rust_file, code).context("cannot write code")?;
+    Ok(Program {
+        program: rust_file.with_extension(exe_suffix()),
+        rust_file,
+        externs,
+        line_map: Some(line_map),
+        expectations,
+        hashed: false,
+        bin_lock: Cell::new(None),
+    })
+}
+
+// compile and run a block, returning true if it behaved as expected,
+// and what it wrote to stdout. Output is only shown for failures.
+fn run_block(
+    md: &Path,
+    block: &Block,
+    options: &SnippetOptions,
+    state: &mut State,
+) -> Result<(bool, String)> {
+    let attributes = &block.attributes;
+    let prog = block_program(md, block, options)?;
+    let edition = state.edition.clone();
+    if let Some(block_edition) = &attributes.edition {
+        state.edition = block_edition.clone();
+    }
+    state.quiet = attributes.compile_fail;
+    let compiled = prog.compile(state);
+    state.edition = edition;
+    state.quiet = false;
+    let compiled = compiled?;
+    if attributes.compile_fail || !compiled || attributes.no_run {
+        return Ok((compiled != attributes.compile_fail, String::new()));
+    }
+    let output = program_command(state, &prog.program)?
+        .output()
+        .with_context(|| format!("can't run program {:?}", prog.program))?;
+    // any `// =>` annotations are checked, and their markers removed
+    let mut stdout = Vec::new();
+    let failed = expect::check(&prog.expectations, &output.stdout[..], &mut stdout)?;
+    let ok = output.status.success() != attributes.should_panic && failed == 0;
+    if !ok {
+        io::Write::write_all(&mut io::stdout(), &stdout)?;
+        if let Some(line_map) = &prog.line_map {
+            line_map.copy_mapped(&prog.rust_file, &output.stderr[..], io::stderr())?;
+        }
+    }
+    Ok((ok, String::from_utf8_lossy(&stdout).into_owned()))
+}
+
+// run all the Rust blocks, printing a line for each, and return
+// true if they all passed
+pub fn run(md: &Path, options: &SnippetOptions, state: &mut State) -> Result<bool> {
+    let text = fs::read_to_string(md).with_context(|| format!("cannot read {}", md.display()))?;
+    let (mut passed, mut failed, mut ignored) = (0, 0, 0);
+    for block in extract_blocks(&text) {
+        let location = format!("{}:{}", md.display(), block.line);
+        if block.attributes.ignore {
+            println!("{} ... ignored", location);
+            ignored += 1;
+            continue;
+        }
+        let (ok, _) = run_block(md, &block, options, state).unwrap_or_else(|e| {
+            eprintln!("error: {:#}", e);
+            (false, String::new())
+        });
+        if ok {
+            println!("{} ... ok", location);
+            passed += 1;
+        } else {
+            println!("{} ... FAILED", location);
+            failed += 1;
+        }
+    }
+    println!(
+        "\n{}: {} passed; {} failed; {} ignored",
+        md.display(),
+        passed,
+        failed,
+        ignored
+    );
+    Ok(failed == 0)
+}
+
+const OUTPUT_MARKER: &str = "<!-- runner-output -->";
+
+// the output block to follow a code block; the fence must be
+// longer than any run of backticks in the output
+fn output_block(output: &str) -> Vec<String> {
+    let output = output.trim_end();
+    if output.is_empty() {
+        return Vec::new();
+    }
+    let mut fence = String::from("```");
+    while output.contains(&fence) {
+        fence.push('`');
+    }
+    let mut lines = vec![
+        String::new(),
+        OUTPUT_MARKER.to_string(),
+        format!("{}text", fence),
+    ];
+    lines.extend(output.lines().map(|l| l.to_string()));
+    lines.push(fence);
+    lines
+}
+
+// the lines following a code block which are a previously woven output
+// block, as a range of line indices
+fn existing_output(lines: &[&str], after: usize) -> Option<(usize, usize)> {
+    let mut i = after;
+    while i < lines.len() && lines[i].trim().is_empty() {
+        i += 1;
+    }
+    if lines.get(i)?.trim() != OUTPUT_MARKER {
+        return None;
+    }
+    let open = lines.get(i + 1)?.trim();
+    let fence = &open[..open.find(|c| c != '`').unwrap_or(open.len())];
+    if fence.len() < 3 {
+        return None;
+    }
+    let close = (i + 2..lines.len()).find(|&j| lines[j].trim() == fence)?;
+    Some((after, close + 1))
+}
+
+// put the output of each Rust block after it, replacing any output from
+// before; `run` gives the output, or None if the block failed. Returns the
+// new text, whether all blocks ran, and whether the text changed.
+fn weave_text<F>(md: &Path, text: &str, check: bool, mut run: F) -> (String, bool, bool)
+where
+    F: FnMut(&Block) -> Option<String>,
+{
+    let lines: Vec<&str> = text.lines().collect();
+    let mut woven: Vec<String> = Vec::new();
+    let (mut next, mut all_ok, mut changed) = (0, true, false);
+    for block in extract_blocks(text) {
+        let a = &block.attributes;
+        if a.ignore || a.no_run || a.compile_fail || block.end <= next {
+            continue;
+        }
+        let location = format!("{}:{}", md.display(), block.line);
+        woven.extend(lines[next..block.end].iter().map(|l| l.to_string()));
+        next = block.end;
+        let existing = existing_output(&lines, block.end);
+        let output = match run(&block) {
+            Some(output) => output,
+            None => {
+                println!("{} ... FAILED", location);
+                all_ok = false;
+                continue;
+            }
+        };
+        let new = output_block(&output);
+        let old = existing.map_or(&lines[0..0], |(start, end)| &lines[start..end]);
+        if new.iter().map(|l| l.as_str()).eq(old.iter().copied()) {
+            println!("{} ... unchanged", location);
+        } else {
+            println!(
+                "{} ... {}",
+                location,
+                if check { "out of date" } else { "updated" }
+            );
+            changed = true;
+        }
+        woven.extend(new);
+        if let Some((_, end)) = existing {
+            next = end;
+        }
+    }
+    woven.extend(lines[next..].iter().map(|l| l.to_string()));
+    let mut woven = woven.join("\n");
+    if text.ends_with('\n') {
+        woven.push('\n');
+    }
+    (woven, all_ok, changed)
+}
+
+// run the Rust blocks and put their output after them, replacing any
+// output from before. With `check`, the file is not changed, but we fail if
+// it would be. Returns true if all went well.
+pub fn weave(md: &Path, options: &SnippetOptions, state: &mut State, check: bool) -> Result<bool> {
+    let text = fs::read_to_string(md).with_context(|| format!("cannot read {}", md.display()))?;
+    let (woven, all_ok, changed) = weave_text(md, &text, check, |block| {
+        match run_block(md, block, options, state) {
+            Ok((true, output)) => Some(output),
+            Ok((false, _)) => None,
+            Err(e) => {
+                eprintln!("error: {:#}", e);
+                None
+            }
+        }
+    });
+    if check {
+        return Ok(all_ok && !changed);
+    }
+    if changed {
+        fs::write(md, woven).with_context(|| format!("cannot write {}", md.display()))?;
+    }
+    Ok(all_ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOC: &str = "# Title\n\n```rust\nprintln!(\"hello\");\n```\n\nSome text.\n";
+
+    fn weave_with(text: &str, output: &str) -> (String, bool, bool) {
+        weave_text(Path::new("doc.md"), text, false, |_| {
+            Some(output.to_string())
+        })
+    }
+
+    #[test]
+    fn longer_fences_need_longer_closing_fences() {
+        let text = "````rust\n```\nlet a = 1;\n````\n```rust\nlet b = 2;\n```\n";
+        let blocks = extract_blocks(text);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!((blocks[0].line, blocks[0].end), (1, 4));
+        assert_eq!((blocks[1].line, blocks[1].end), (5, 7));
+    }
+
+    #[test]
+    fn weaving_twice_changes_nothing() {
+        let (once, ok, changed) = weave_with(DOC, "hello\n");
+        assert!(ok && changed);
+        assert_eq!(
+            once,
+            "# Title\n\n```rust\nprintln!(\"hello\");\n```\n\n\
+             <!-- runner-output -->\n```text\nhello\n```\n\nSome text.\n"
+        );
+        let (twice, ok, changed) = weave_with(&once, "hello\n");
+        assert!(ok && !changed);
+        assert_eq!(twice, once);
+    }
+
+    #[test]
+    fn output_with_fences_is_read_back() {
+        let output = "```rust\nfn main() {}\n```\n";
+        let (once, _, changed) = weave_with(DOC, output);
+        assert!(changed);
+        assert!(once.contains("\n````text\n```rust\nfn main() {}\n```\n````\n"));
+        assert_eq!(extract_blocks(&once).len(), 1);
+        let (twice, _, changed) = weave_with(&once, output);
+        assert!(!changed);
+        assert_eq!(twice, once);
+    }
+
+    #[test]
+    fn only_changed_output_is_replaced() {
+        let (once, _, _) = weave_with(DOC, "hello\n");
+        let (again, _, changed) = weave_with(&once, "goodbye\n");
+        assert!(changed);
+        assert_eq!(again, once.replace("\nhello\n", "\ngoodbye\n"));
+        let (failed, ok, changed) = weave_text(Path::new("doc.md"), &once, false, |_| None);
+        assert!(!ok && !changed);
+        assert_eq!(failed, once);
+    }
+}
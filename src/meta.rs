@@ -4,15 +4,15 @@
 extern crate csv;
 extern crate json;
 use anyhow::{bail, Context, Result};
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
 use std::path::{Path, PathBuf};
 
-use super::crate_utils::proper_crate_name;
+use super::crate_utils::{proper_crate_name, Dependency};
 use crate::cache::static_cache_dir;
 use crate::cargo_lock;
 
-use semver::Version;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
 
 fn as_str(v: &json::JsonValue) -> &str {
@@ -68,6 +68,7 @@ fn read_entry(line: &str) -> Result<Option<MetaEntry>> {
                 debug_name: filename.to_string(),
                 release_name: filename.to_string(),
                 path: path.to_path_buf(),
+                alias: String::new(),
             }))
         } else {
             Ok(None)
@@ -77,6 +78,16 @@ fn read_entry(line: &str) -> Result<Option<MetaEntry>> {
     }
 }
 
+// a version like '1.9' means any 1.9.x, as with Cargo's tilde requirements
+pub fn version_req(vs: &str) -> Result<VersionReq> {
+    let req = if vs.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("~{}", vs)
+    } else {
+        vs.to_string()
+    };
+    VersionReq::parse(&req).with_context(|| format!("bad version {:?}", vs))
+}
+
 fn file_name(cache: &Path) -> PathBuf {
     cache.join("cargo.meta")
 }
@@ -90,6 +101,10 @@ pub struct MetaEntry {
     pub debug_name: String,
     pub release_name: String,
     pub path: PathBuf,
+    // the dependency name in the static cache Cargo.toml, if this
+    // is a direct dependency; it differs from the package if renamed
+    #[serde(default)]
+    pub alias: String,
 }
 
 pub struct Meta {
@@ -123,7 +138,7 @@ impl Meta {
     pub fn get_meta_entries(&self, name: &str) -> Vec<&MetaEntry> {
         self.entries
             .iter()
-            .filter(|e| e.package == name || e.crate_name == name)
+            .filter(|e| e.package == name || e.crate_name == name || e.alias == name)
             .collect()
     }
 
@@ -139,8 +154,27 @@ impl Meta {
         Some(v[v.len() - 1])
     }
 
-    pub fn get_full_crate_name(&self, name: &str, debug: bool) -> Option<String> {
-        self.get_meta_entry(name).map(|e| {
+    // the latest version of the crate which matches the requirement
+    pub fn get_meta_entry_matching(&self, name: &str, req: &VersionReq) -> Option<&MetaEntry> {
+        self.get_meta_entries(name)
+            .into_iter()
+            .filter(|e| req.matches(&e.version))
+            .max_by(|a, b| a.version.cmp(&b.version))
+    }
+
+    // the library for the crate, which is the latest version unless
+    // a particular version is asked for
+    pub fn get_full_crate_name(
+        &self,
+        name: &str,
+        req: Option<&VersionReq>,
+        debug: bool,
+    ) -> Option<String> {
+        match req {
+            Some(req) => self.get_meta_entry_matching(name, req),
+            None => self.get_meta_entry(name),
+        }
+        .map(|e| {
             if debug {
                 e.debug_name.clone()
             } else {
@@ -194,13 +228,53 @@ impl Meta {
         Ok(())
     }
 
+    // the debug build has different library names
+    pub fn debug(&mut self, txt: String) -> Result<()> {
+        for line in txt.lines() {
+            if let Some(d) = read_entry(line)? {
+                let name = proper_crate_name(&d.crate_name);
+                for e in self.entries.iter_mut() {
+                    if e.package == d.package && e.version == d.version && e.crate_name == name {
+                        e.debug_name = d.debug_name.clone();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // match the direct dependencies with their entries, so that renamed
+    // dependencies can be found by their new names
+    pub fn set_aliases(&mut self, deps: &[Dependency]) {
+        for e in self.entries.iter_mut() {
+            let dep = deps.iter().find(|d| {
+                d.package == e.package
+                    && match (&d.path, &d.version) {
+                        (Some(path), _) => e.path.starts_with(path),
+                        (None, Some(vs)) => {
+                            VersionReq::parse(vs).is_ok_and(|r| r.matches(&e.version))
+                        }
+                        (None, None) => true,
+                    }
+            });
+            if let Some(dep) = dep {
+                e.alias = dep.name.clone();
+            }
+        }
+    }
+
+    // written to a temporary file first, so that other runners
+    // never see it half-written
     pub fn update(self, cache: &Path) -> Result<()> {
         let meta_f = file_name(cache);
-        let mut wtr = csv::Writer::from_writer(io::BufWriter::new(File::create(&meta_f)?));
+        let tmp = meta_f.with_extension("meta.tmp");
+        let mut wtr = csv::Writer::from_writer(io::BufWriter::new(File::create(&tmp)?));
         for e in self.entries {
             wtr.serialize(e)?;
         }
         wtr.flush()?;
+        drop(wtr);
+        fs::rename(&tmp, &meta_f).context("cannot replace cargo.meta")?;
         Ok(())
     }
 }
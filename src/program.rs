@@ -0,0 +1,392 @@
+// a program ready to be compiled and run, which is either
+// generated from a snippet or a proper Rust program
+use crate::cache::{self, CacheLock};
+use crate::compile::{compile_crate, extract_externs, massage_snippet, program_command, Wrapper};
+use crate::config;
+use crate::crate_utils;
+use crate::expect::{self, Expectation};
+use crate::hash::Fnv;
+use crate::linemap::LineMap;
+use crate::snippet;
+use crate::state::{SnippetOptions, State};
+use anyhow::{bail, Context, Result};
+use std::cell::Cell;
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX, EXE_SUFFIX};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+
+pub struct Program {
+    pub rust_file: PathBuf,
+    pub program: PathBuf,
+    pub externs: Vec<String>,
+    // only for snippets
+    pub line_map: Option<LineMap>,
+    // what statements annotated with `// =>` should print
+    pub expectations: Vec<Expectation>,
+    // expressions are named by a hash of everything that goes into
+    // the build, so the program can be reused if it exists
+    pub hashed: bool,
+    // keeps --gc away from a cached expression until it has started
+    pub bin_lock: Cell<Option<CacheLock>>,
+}
+
+pub fn exe_suffix() -> &'static str {
+    EXE_SUFFIX.trim_start_matches('.')
+}
+
+// test harnesses, benchmarks and comparison builds must not overwrite
+// the program built from the same file
+fn wrapped_name(file: &Path, wrapper: Wrapper, variant: &str) -> String {
+    let name = file.file_stem().unwrap().to_string_lossy();
+    format!("{}{}{}", name, wrapper.suffix(), variant)
+}
+
+// the generated code, the build settings, the compiler and the
+// libraries we link against
+fn build_hash(code: &str, externs: &[String], state: &State) -> Result<String> {
+    let mut h = Fnv::new();
+    code.hash(&mut h);
+    crate_utils::rustc_version()?.hash(&mut h);
+    (
+        state.build_static,
+        state.optimize,
+        state.test,
+        state.bench,
+        &state.edition,
+        state.libc,
+        &state.features,
+        &state.link,
+        &state.cfg,
+    )
+        .hash(&mut h);
+    let mut externs = externs.to_vec();
+    externs.extend(state.externs.iter().cloned());
+    externs.sort();
+    externs.hash(&mut h);
+    let mut versions: Vec<_> = state
+        .versions
+        .iter()
+        .map(|(name, req)| format!("{}={}", name, req))
+        .collect();
+    versions.sort();
+    versions.hash(&mut h);
+    if let Some(profile) = &state.profile {
+        config::profile(profile)?.hash(&mut h);
+    }
+    if state.build_static {
+        // the library names change when the static cache is rebuilt
+        fs::read(cache::static_cache_dir()?.join("cargo.meta"))
+            .unwrap_or_default()
+            .hash(&mut h);
+    } else {
+        let cache = cache::get_cache(state)?;
+        for c in externs {
+            let dll = cache.join(format!("{}{}{}", DLL_PREFIX, c, DLL_SUFFIX));
+            dll.metadata().and_then(|m| m.modified()).ok().hash(&mut h);
+        }
+    }
+    Ok(format!("{:016x}", h.finish()))
+}
+
+// another runner may be using the same file, so it must appear all at once
+fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    let tmp = path.with_extension(format!("{}.tmp", process::id()));
+    fs::write(&tmp, contents).context("cannot write code")?;
+    fs::rename(&tmp, path).context("cannot write code")
+}
+
+impl Program {
+    // `file` is the source file, if there is one; expressions
+    // get a made-up name
+    pub fn new(
+        code: String,
+        file: Option<&Path>,
+        options: &SnippetOptions,
+        state: &mut State,
+    ) -> Result<Program> {
+        // ALL executables go into the Runner bin directory, each script's
+        // in its own directory...
+        let mut bin = cache::runner_directory()?.join("bin");
+        let mut externs = Vec::new();
+
+        // proper Rust programs are accepted
+        if !snippet::is_program(&code) {
+            // otherwise we must create a proper program from the snippet
+            // and write this as a file in the Runner bin directory...
+            let (code, deduced_externs, line_map, expectations) = massage_snippet(
+                code,
+                file,
+                options.prelude.clone(),
+                &options.crates,
+                options.body_prelude.clone(),
+                options.wrapper,
+            )?;
+            let hashed = file.is_none();
+            let mut bin_lock = None;
+            if let Some(file) = file {
+                let name = wrapped_name(file, options.wrapper, &state.variant);
+                bin = cache::script_directory(file)?.join(name);
+                bin.set_extension("rs");
+                fs::write(&bin, &code).context("cannot write code")?;
+            } else {
+                // expressions are kept under their hash
+                bin_lock = Some(cache::lock_bin(false)?);
+                bin.push("expr");
+                fs::create_dir_all(&bin).context("cannot create expression directory")?;
+                let hash = build_hash(&code, &deduced_externs, state)?;
+                bin.push(format!("{}{}.rs", hash, options.wrapper.suffix()));
+                if !bin.exists() {
+                    write_atomically(&bin, &code)?;
+                }
+            }
+            Ok(Program {
+                program: bin.with_extension(exe_suffix()),
+                rust_file: bin,
+                externs: deduced_externs,
+                line_map: Some(line_map),
+                expectations,
+                hashed,
+                bin_lock: Cell::new(bin_lock),
+            })
+        } else {
+            let file = file.context("expecting a file for a program")?;
+            let mut static_externs = 0;
+            // we are given a proper Rust source file, and deduce the crates needed for
+            // static linking from the source
+            for line in code.lines() {
+                if extract_externs(line, &mut externs) {
+                    static_externs += 1;
+                }
+            }
+            if static_externs > 0 {
+                state.build_static = true;
+            }
+            // the 'proper' case - use the file name part
+            let name = wrapped_name(file, options.wrapper, &state.variant);
+            bin = cache::script_directory(file)?.join(name);
+            Ok(Program {
+                program: bin.with_extension(exe_suffix()),
+                rust_file: file.to_path_buf(),
+                externs,
+                line_map: None,
+                expectations: Vec::new(),
+                hashed: false,
+                bin_lock: Cell::new(None),
+            })
+        }
+    }
+
+    // with -R, a program is only rebuilt if anything that goes into it has
+    // changed, including the files the script pulls in
+    pub fn fingerprint(&self, source: &Path, state: &State) -> Result<String> {
+        let mut code = fs::read_to_string(&self.rust_file).context("cannot read code")?;
+        let source_code = fs::read_to_string(source).context("cannot read file")?;
+        let dir = source.parent().unwrap_or(Path::new("."));
+        for file in snippet::dependencies(&source_code, dir) {
+            code += &fs::read_to_string(file).unwrap_or_default();
+        }
+        build_hash(&code, &self.externs, state)
+    }
+
+    fn fingerprint_file(&self) -> PathBuf {
+        self.program.with_extension("fingerprint")
+    }
+
+    // the program exists, and was built from exactly these inputs
+    pub fn is_current(&self, fingerprint: &str) -> bool {
+        self.program.exists()
+            && fs::read_to_string(self.fingerprint_file()).is_ok_and(|f| f == fingerprint)
+    }
+
+    pub fn save_fingerprint(&self, fingerprint: &str) -> Result<()> {
+        fs::write(self.fingerprint_file(), fingerprint).context("cannot write fingerprint")
+    }
+
+    pub fn compile(&self, state: &State) -> Result<bool> {
+        if !self.hashed {
+            // whatever is built now may not match the inputs -R last saw,
+            // so -R must not trust the program until it saves a new one
+            match fs::remove_file(self.fingerprint_file()) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(e).context("cannot remove fingerprint")
+                }
+                _ => {}
+            }
+            return compile_crate(
+                state,
+                "",
+                &self.rust_file,
+                Some(&self.program),
+                self.externs.clone(),
+                Vec::new(),
+                self.line_map.as_ref(),
+            );
+        }
+        if self.program.exists() {
+            if state.verbose {
+                println!("reusing {}", self.program.display());
+            }
+            // so --gc knows it is still used
+            if let Ok(f) = fs::File::open(&self.program) {
+                let _ = f.set_modified(SystemTime::now());
+            }
+            return Ok(true);
+        }
+        // build under a private name, so that a concurrent runner
+        // never sees a half-written program
+        let program = self
+            .program
+            .with_file_name(format!(
+                "{}-{}",
+                self.program.file_stem().unwrap().to_string_lossy(),
+                process::id()
+            ))
+            .with_extension(exe_suffix());
+        let compiled = compile_crate(
+            state,
+            "",
+            &self.rust_file,
+            Some(&program),
+            self.externs.clone(),
+            Vec::new(),
+            self.line_map.as_ref(),
+        )?;
+        if compiled {
+            fs::rename(&program, &self.program).context("cannot rename program")?;
+        }
+        Ok(compiled)
+    }
+
+    // run to completion, returning the exit code and everything printed
+    // (stdout, then stderr) with `// =>` markers removed and locations mapped
+    pub fn output(&self, state: &State) -> Result<(Option<i32>, String)> {
+        let output = program_command(state, &self.program)?
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("can't run program {:?}", self.program))?;
+        let mut text = Vec::new();
+        expect::check(&self.expectations, &output.stdout[..], &mut text)?;
+        match &self.line_map {
+            Some(line_map) => {
+                line_map.copy_mapped(&self.rust_file, &output.stderr[..], &mut text)?
+            }
+            None => text.extend(&output.stderr),
+        }
+        Ok((
+            output.status.code(),
+            String::from_utf8_lossy(&text).into_owned(),
+        ))
+    }
+
+    // start the compiled program. Panics in snippets should point at the
+    // snippet, not the generated file, so stderr goes through the line map.
+    // A test harness reports failures on stdout, so that is mapped as well.
+    pub fn start(&self, state: &State, args: &[String]) -> Result<Running> {
+        self.spawn(state, args, false)
+    }
+
+    // like `start`, but the caller reads the program's stdout
+    pub fn start_captured(&self, state: &State, args: &[String]) -> Result<Running> {
+        self.spawn(state, args, true)
+    }
+
+    fn spawn(&self, state: &State, args: &[String], capture: bool) -> Result<Running> {
+        let mut builder = program_command(state, &self.program)?;
+        builder.args(args);
+        if self.line_map.is_some() {
+            builder.stderr(Stdio::piped());
+        }
+        let check = !capture && !self.expectations.is_empty();
+        if capture || check || (self.line_map.is_some() && state.test) {
+            builder.stdout(Stdio::piped());
+        }
+        let mut child = builder
+            .spawn()
+            .with_context(|| format!("can't run program {:?}", self.program))?;
+        self.bin_lock.take();
+        let mut output = Vec::new();
+        if let Some(line_map) = &self.line_map {
+            let map = |input: Box<dyn Read + Send>, output: Box<dyn Write + Send>| {
+                let (line_map, rust_file) = (line_map.clone(), self.rust_file.clone());
+                thread::spawn(move || {
+                    line_map.copy_mapped(&rust_file, BufReader::new(input), output)
+                })
+            };
+            output.push(map(
+                Box::new(child.stderr.take().unwrap()),
+                Box::new(io::stderr()),
+            ));
+            if !capture && !check {
+                if let Some(stdout) = child.stdout.take() {
+                    output.push(map(Box::new(stdout), Box::new(io::stdout())));
+                }
+            }
+        }
+        let checks = if check {
+            let stdout = BufReader::new(child.stdout.take().unwrap());
+            let expectations = self.expectations.clone();
+            Some(thread::spawn(move || {
+                expect::check(&expectations, stdout, io::stdout())
+            }))
+        } else {
+            None
+        };
+        Ok(Running {
+            child,
+            output,
+            checks,
+        })
+    }
+}
+
+pub struct Running {
+    pub child: Child,
+    output: Vec<JoinHandle<io::Result<()>>>,
+    // counts the `// =>` expectations which were not met
+    checks: Option<JoinHandle<io::Result<usize>>>,
+}
+
+impl Running {
+    fn finish(mut self) -> Result<(ExitStatus, usize)> {
+        let status = self.child.wait().context("program did not run")?;
+        for output in self.output {
+            output
+                .join()
+                .expect("output thread panicked")
+                .context("cannot read program output")?;
+        }
+        let failed = match self.checks {
+            Some(checks) => checks
+                .join()
+                .expect("output thread panicked")
+                .context("cannot read program output")?,
+            None => 0,
+        };
+        Ok((status, failed))
+    }
+
+    // the program's exit status; it is an error if it did not print what
+    // it was expected to
+    pub fn wait(self) -> Result<ExitStatus> {
+        let (status, failed) = self.finish()?;
+        if status.success() && failed > 0 {
+            bail!("{} of the expected outputs did not match", failed);
+        }
+        Ok(status)
+    }
+
+    // stop the program if it is still running
+    pub fn kill(mut self) -> Result<()> {
+        if self.child.try_wait()?.is_none() {
+            // it may finish in the meantime
+            let _ = self.child.kill();
+        }
+        self.finish()?;
+        Ok(())
+    }
+}
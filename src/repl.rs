@@ -0,0 +1,253 @@
+// An interactive loop built on the snippet machinery.
+// Each input is compiled and run along with the inputs that came before it
+// (there is no persistent process) so the generated program prints a marker,
+// and only output following the marker is shown.
+use crate::cache;
+use crate::compile::{compile_crate, massage_snippet, program_command, Wrapper};
+use crate::program::exe_suffix;
+use crate::snippet::{split_snippet, Place};
+use crate::state::{SnippetOptions, State};
+use anyhow::{Context, Result};
+use proc_macro2::{LineColumn, TokenStream};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{self, Stdio};
+use std::thread;
+
+const MARKER: &str = "\u{1}runner-repl";
+
+const HELPERS: &str = "fn __runner_show<T: std::fmt::Debug>(v: &T) {
+    if std::any::type_name::<T>() != \"()\" {
+        println!(\"{:?}\", v);
+    }
+}
+fn __runner_type_of<T>(_: &T) -> &'static str {
+    std::any::type_name::<T>()
+}
+";
+
+const HELP: &str = "Enter items, statements or an expression; the value of a final
+expression (without a semicolon) is printed. Each input is run along with
+all the statements before it, so their side effects (like writing files)
+happen again, although their earlier output is not shown.
+  :type EXPR   show the type of an expression
+  :reset       forget all previous input
+  :save FILE   save the input so far as a snippet
+  :crates      list crates in the static cache (or given crates)
+  :quit        leave (so does end of input)";
+
+pub struct Repl {
+    state: State,
+    options: SnippetOptions,
+    items: Vec<String>,
+    statements: Vec<String>,
+    rust_file: PathBuf,
+    program: PathBuf,
+}
+
+// only pass through what follows the marker
+fn copy_after_marker(input: impl Read, mut output: impl Write) -> io::Result<()> {
+    let mut seen = false;
+    for line in BufReader::new(input).split(b'\n') {
+        let line = line?;
+        if seen {
+            output.write_all(&line)?;
+            output.write_all(b"\n")?;
+            output.flush()?;
+        } else if line == MARKER.as_bytes() {
+            seen = true;
+        }
+    }
+    Ok(())
+}
+
+// are there brackets, strings or comments still open? Then we need more
+// input. Mismatched brackets are left to the compiler to complain about.
+fn incomplete(text: &str) -> bool {
+    let err = match text.parse::<TokenStream>() {
+        Ok(_) => return false,
+        Err(err) => err,
+    };
+    // the lexer points at whatever it could not close
+    let LineColumn { line, column } = err.span().start();
+    let rest: String = text
+        .lines()
+        .nth(line - 1)
+        .unwrap_or_default()
+        .chars()
+        .skip(column)
+        .collect();
+    ["(", "[", "{", "\"", "r\"", "r#", "b\"", "br", "/*"]
+        .iter()
+        .any(|open| rest.starts_with(open))
+}
+
+impl Repl {
+    pub fn new(mut state: State, options: SnippetOptions) -> Result<Repl> {
+        // input is always run as a program
+        state.test = false;
+        // several REPLs may be running at once
+        let rust_file = cache::runner_directory()?
+            .join("bin")
+            .join(format!("repl-{}.rs", process::id()));
+        let program = rust_file.with_extension(exe_suffix());
+        Ok(Repl {
+            state,
+            options,
+            items: Vec::new(),
+            statements: Vec::new(),
+            rust_file,
+            program,
+        })
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let stdin = io::stdin();
+        let mut input = String::new();
+        loop {
+            print!("{}", if input.is_empty() { ">> " } else { ".. " });
+            io::stdout().flush()?;
+            if stdin.lock().read_line(&mut input)? == 0 {
+                println!();
+                return Ok(());
+            }
+            if incomplete(&input) {
+                continue;
+            }
+            let text = input.trim().to_string();
+            input.clear();
+            let res = if let Some(command) = text.strip_prefix(':') {
+                let (command, arg) = command.split_once(' ').unwrap_or((command, ""));
+                let arg = arg.trim();
+                match command {
+                    "q" | "quit" => return Ok(()),
+                    _ => self.command(command, arg),
+                }
+            } else if !text.is_empty() {
+                self.eval(&text)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = res {
+                eprintln!("error: {:#}", e);
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str, arg: &str) -> Result<()> {
+        match command {
+            "type" => {
+                let show = format!("println!(\"{{}}\", __runner_type_of(&({})));", arg);
+                self.execute(&[], &[], Some(&show))?;
+            }
+            "reset" => {
+                self.items.clear();
+                self.statements.clear();
+            }
+            "save" => {
+                let mut code = self.items.join("\n");
+                code.push('\n');
+                code += &self.statements.join("\n");
+                code.push('\n');
+                fs::write(arg, code).with_context(|| format!("cannot write {}", arg))?;
+            }
+            "crates" => {
+                let names = arg.split_whitespace().map(|s| s.to_string()).collect();
+                cache::get_metadata()?.dump_crates(names, false)?;
+            }
+            "help" => println!("{}", HELP),
+            _ => println!("unknown command :{}\n{}", command, HELP),
+        }
+        Ok(())
+    }
+
+    fn eval(&mut self, input: &str) -> Result<()> {
+        let parsed = split_snippet(input);
+        let (mut items, mut statements, mut value) = (Vec::new(), Vec::new(), None);
+        let last = parsed.parts.len().saturating_sub(1);
+        for (i, part) in parsed.parts.iter().enumerate() {
+            match part.place {
+                Place::Body if part.value && i == last => value = Some(part.text),
+                Place::Body => statements.push(part.text.to_string()),
+                // inner attributes must come first
+                Place::CrateBegin => items.insert(0, part.text.to_string()),
+                _ => items.push(part.text.to_string()),
+            }
+        }
+        let show = value.map(|v| format!("__runner_show(&({}));", v));
+        if self.execute(&items, &statements, show.as_deref())? {
+            self.items.extend(items);
+            self.statements.extend(statements);
+            // keep any side effects, without moving anything
+            if let Some(v) = value {
+                self.statements.push(format!("let _ = {};", v));
+            }
+        }
+        Ok(())
+    }
+
+    // compile and run all previous input plus the new input,
+    // returning true if all went well
+    fn execute(&self, items: &[String], statements: &[String], show: Option<&str>) -> Result<bool> {
+        let mut code = String::from(HELPERS);
+        for line in self.items.iter().chain(items) {
+            code += line;
+            code.push('\n');
+        }
+        for line in &self.statements {
+            code += line;
+            code.push('\n');
+        }
+        code += &format!(
+            "println!(\"{0}\");\neprintln!(\"{0}\");\n",
+            MARKER.escape_default()
+        );
+        for line in statements.iter().map(|s| s.as_str()).chain(show) {
+            code += line;
+            code.push('\n');
+        }
+
+        let (code, externs, line_map, _) = massage_snippet(
+            code,
+            None,
+            self.options.prelude.clone(),
+            &self.options.crates,
+            self.options.body_prelude.clone(),
+            Wrapper::Main {
+                debug_errors: false,
+            },
+        )?;
+        fs::write(&self.rust_file, &code).context("cannot write code")?;
+        if !compile_crate(
+            &self.state,
+            "",
+            &self.rust_file,
+            Some(&self.program),
+            externs,
+            Vec::new(),
+            Some(&line_map),
+        )? {
+            return Ok(false);
+        }
+
+        let mut child = program_command(&self.state, &self.program)?
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("can't run program {:?}", self.program))?;
+        let stderr = child.stderr.take().unwrap();
+        let errors = thread::spawn(move || copy_after_marker(stderr, io::stderr()));
+        copy_after_marker(child.stdout.take().unwrap(), io::stdout())?;
+        errors.join().expect("stderr thread panicked")?;
+        Ok(child.wait().context("program did not run")?.success())
+    }
+}
+
+impl Drop for Repl {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.rust_file);
+        let _ = fs::remove_file(&self.program);
+    }
+}
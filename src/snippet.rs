@@ -0,0 +1,372 @@
+// parsing snippets with syn, so that we know which parts
+// belong at module scope and which are statements for `run`
+use crate::compile::extract_externs;
+use proc_macro2::LineColumn;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use syn::parse::{ParseStream, Parser};
+use syn::spanned::Spanned;
+use syn::{Attribute, Block, Item, Stmt, UseTree};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Place {
+    // inner attributes like #![feature(..)]
+    CrateBegin,
+    // imports, extern crates and macro definitions, which go before `run`
+    Prefix,
+    // all other items, which go after `main`
+    Item,
+    // statements which make up the body of `run`
+    Body,
+}
+
+// a top-level piece of a snippet, as a slice of the original text.
+// `line` is 1-based and `column` is in chars, like proc-macro2 spans.
+#[derive(Debug)]
+pub struct Part<'a> {
+    pub place: Place,
+    pub line: usize,
+    pub column: usize,
+    pub text: &'a str,
+    // an expression statement without a semicolon, which has a value
+    pub value: bool,
+}
+
+pub struct Parsed<'a> {
+    pub parts: Vec<Part<'a>>,
+    pub externs: Vec<String>,
+}
+
+fn parse_snippet(code: &str) -> syn::Result<(Vec<Attribute>, Vec<Stmt>)> {
+    let parser = |input: ParseStream| {
+        let attrs = input.call(Attribute::parse_inner)?;
+        let stmts = input.call(Block::parse_within)?;
+        Ok((attrs, stmts))
+    };
+    parser.parse_str(&blank_shebang(code))
+}
+
+// does the snippet parse as items and statements?
+pub fn parses(code: &str) -> bool {
+    parse_snippet(code).is_ok()
+}
+
+fn is_shebang(code: &str) -> bool {
+    code.starts_with("#!") && !code.starts_with("#![")
+}
+
+// a shebang line is not Rust; blank it out but keep the line count
+fn blank_shebang(code: &str) -> String {
+    if is_shebang(code) {
+        let eol = code.find('\n').unwrap_or(code.len());
+        code[eol..].to_string()
+    } else {
+        code.to_string()
+    }
+}
+
+fn byte_offset(code: &str, line_starts: &[usize], lc: LineColumn) -> usize {
+    let start = line_starts[lc.line - 1];
+    code[start..]
+        .char_indices()
+        .nth(lc.column)
+        .map(|(i, _)| start + i)
+        .unwrap_or(code.len())
+}
+
+fn item_name(item: &Item) -> Option<String> {
+    let ident = match item {
+        Item::Enum(i) => &i.ident,
+        Item::Struct(i) => &i.ident,
+        Item::Union(i) => &i.ident,
+        Item::Trait(i) => &i.ident,
+        Item::Type(i) => &i.ident,
+        Item::Mod(i) => &i.ident,
+        Item::Fn(i) => &i.sig.ident,
+        Item::Const(i) => &i.ident,
+        Item::Static(i) => &i.ident,
+        _ => return None,
+    };
+    Some(ident.to_string())
+}
+
+// the first path segments of a use tree are (possibly) crate names
+fn use_roots(tree: &UseTree, roots: &mut Vec<String>) {
+    match tree {
+        UseTree::Path(p) => roots.push(p.ident.to_string()),
+        UseTree::Name(n) => roots.push(n.ident.to_string()),
+        UseTree::Rename(r) => roots.push(r.ident.to_string()),
+        UseTree::Group(g) => {
+            for t in &g.items {
+                use_roots(t, roots);
+            }
+        }
+        UseTree::Glob(_) => {}
+    }
+}
+
+// if a snippet does not parse, then go by lines as runner used to: leading
+// imports and inner attributes go to the top, and the rest into the body,
+// where rustc will complain about it
+fn split_lines(code: &str) -> Parsed<'_> {
+    let mut parts = Vec::new();
+    let mut externs = Vec::new();
+    let mut offset = 0;
+    for (i, line) in code.split_inclusive('\n').enumerate() {
+        let start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+        let place = if (i == 0 && is_shebang(line)) || trimmed.is_empty() {
+            continue;
+        } else if trimmed.starts_with("#![") {
+            Place::CrateBegin
+        } else if trimmed.starts_with("use ")
+            || trimmed.starts_with("extern crate ")
+            || trimmed.starts_with("#[macro_use]")
+            || trimmed.starts_with("//")
+        {
+            extract_externs(trimmed, &mut externs);
+            Place::Prefix
+        } else {
+            parts.push(Part {
+                place: Place::Body,
+                line: i + 1,
+                column: 0,
+                text: &code[start..],
+                value: false,
+            });
+            break;
+        };
+        parts.push(Part {
+            place,
+            line: i + 1,
+            column: 0,
+            text: line.trim_end(),
+            value: false,
+        });
+    }
+    Parsed { parts, externs }
+}
+
+// 1-based line and column in chars of a byte offset
+fn line_column(code: &str, offset: usize) -> (usize, usize) {
+    let before = &code[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count(),
+    )
+}
+
+// split a snippet into its parts. Comments between the parts
+// go with the part which follows them.
+pub fn split_snippet(code: &str) -> Parsed<'_> {
+    let (attrs, stmts) = match parse_snippet(code) {
+        Ok(res) => res,
+        Err(_) => return split_lines(code),
+    };
+    let mut line_starts = vec![0];
+    line_starts.extend(code.match_indices('\n').map(|(i, _)| i + 1));
+    let mut prev_end = if is_shebang(code) {
+        code.find('\n').unwrap_or(code.len())
+    } else {
+        0
+    };
+    let mut part = |place, span: proc_macro2::Span, value| {
+        let mut start = byte_offset(code, &line_starts, span.start());
+        let end = byte_offset(code, &line_starts, span.end());
+        let gap = &code[prev_end..start];
+        if let Some(comment) = gap.find(|c: char| !c.is_whitespace()) {
+            start = prev_end + comment;
+        }
+        prev_end = end;
+        let (line, column) = line_column(code, start);
+        Part {
+            place,
+            line,
+            column,
+            text: &code[start..end],
+            value,
+        }
+    };
+
+    let mut parts: Vec<_> = attrs
+        .iter()
+        .map(|a| part(Place::CrateBegin, a.span(), false))
+        .collect();
+    let mut roots = Vec::new();
+    let mut local_names = HashSet::new();
+    for stmt in &stmts {
+        let place = if let Stmt::Item(item) = stmt {
+            if let Some(name) = item_name(item) {
+                local_names.insert(name);
+            }
+            match item {
+                Item::Use(u) => {
+                    use_roots(&u.tree, &mut roots);
+                    Place::Prefix
+                }
+                Item::ExternCrate(c) => {
+                    roots.push(c.ident.to_string());
+                    Place::Prefix
+                }
+                // macro_rules! is textually scoped, so must come before `run`
+                Item::Macro(m) if m.ident.is_some() => Place::Prefix,
+                _ => Place::Item,
+            }
+        } else {
+            Place::Body
+        };
+        let value = matches!(stmt, Stmt::Expr(_, None))
+            || matches!(stmt, Stmt::Macro(m) if m.semi_token.is_none());
+        parts.push(part(place, stmt.span(), value));
+    }
+
+    let mut externs = Vec::new();
+    for name in roots {
+        if !["std", "core", "alloc", "crate", "self", "super"].contains(&name.as_str())
+            && !local_names.contains(&name)
+            && !externs.contains(&name)
+        {
+            externs.push(name);
+        }
+    }
+    Parsed { parts, externs }
+}
+
+// proper Rust programs have a top-level `fn main`
+pub fn is_program(code: &str) -> bool {
+    match parse_snippet(code) {
+        Ok((_, stmts)) => stmts.iter().any(|s| match s {
+            Stmt::Item(Item::Fn(f)) => f.sig.ident == "main",
+            _ => false,
+        }),
+        Err(_) => code.contains("fn main"),
+    }
+}
+
+// files that a snippet or program pulls in with `mod` declarations
+// or the include macros, relative to its directory
+pub fn dependencies(code: &str, dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Ok((_, stmts)) = parse_snippet(code) {
+        for stmt in &stmts {
+            if let Stmt::Item(Item::Mod(m)) = stmt {
+                if m.content.is_none() {
+                    let name = m.ident.to_string();
+                    files.push(dir.join(format!("{}.rs", name)));
+                    files.push(dir.join(&name).join("mod.rs"));
+                }
+            }
+        }
+    }
+    for mac in ["include_str!", "include_bytes!", "include!"] {
+        let mut s = code;
+        while let Some(pos) = s.find(mac) {
+            s = &s[pos + mac.len()..];
+            let arg = s
+                .trim_start()
+                .strip_prefix('(')
+                .and_then(|rest| rest.trim_start().strip_prefix('"'))
+                .and_then(|rest| rest.split_once('"'));
+            if let Some((path, _)) = arg {
+                files.push(dir.join(path));
+            }
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(code: &str) -> Vec<(Place, usize, &str, bool)> {
+        split_snippet(code)
+            .parts
+            .into_iter()
+            .map(|p| (p.place, p.line, p.text, p.value))
+            .collect()
+    }
+
+    #[test]
+    fn items_and_statements() {
+        let code = "#![allow(unused)]\nlet x = 1;\nfn f() -> i32 {\n    2\n}\nstruct S;\nmacro_rules! m { () => {} }\n";
+        assert_eq!(
+            parts(code),
+            [
+                (Place::CrateBegin, 1, "#![allow(unused)]", false),
+                (Place::Body, 2, "let x = 1;", false),
+                (Place::Item, 3, "fn f() -> i32 {\n    2\n}", false),
+                (Place::Item, 6, "struct S;", false),
+                (Place::Prefix, 7, "macro_rules! m { () => {} }", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_line_use() {
+        let code = "use regex::{\n    Regex,\n    Captures,\n};\nuse std::fmt;\nmod local {}\nuse local::*;\n";
+        let parsed = split_snippet(code);
+        assert_eq!(parsed.parts[0].place, Place::Prefix);
+        assert_eq!(
+            parsed.parts[0].text,
+            "use regex::{\n    Regex,\n    Captures,\n};"
+        );
+        assert_eq!(parsed.externs, ["regex"]);
+    }
+
+    #[test]
+    fn shebang() {
+        let code = "#!/usr/bin/env runner\nlet x = 1;\nx\n";
+        assert_eq!(
+            parts(code),
+            [
+                (Place::Body, 2, "let x = 1;", false),
+                (Place::Body, 3, "x", true)
+            ]
+        );
+    }
+
+    #[test]
+    fn trailing_expression() {
+        let code = "let v = vec![1, 2];\nv.len()";
+        let parsed = split_snippet(code);
+        let last = parsed.parts.last().unwrap();
+        assert_eq!((last.text, last.value), ("v.len()", true));
+        assert!(!parsed.parts[0].value);
+    }
+
+    #[test]
+    fn comments_are_kept() {
+        let code = "let x = 1; // one\n// two\nlet y = 2;\n/* three */ println!(\"{}\", x + y);\n";
+        let parsed = split_snippet(code);
+        assert_eq!(parsed.parts[1].text, "// one\n// two\nlet y = 2;");
+        assert_eq!((parsed.parts[1].line, parsed.parts[1].column), (1, 11));
+        assert_eq!(parsed.parts[2].text, "/* three */ println!(\"{}\", x + y);");
+    }
+
+    #[test]
+    fn parse_failure_goes_by_lines() {
+        let code = "#!/usr/bin/env runner\n#![allow(unused)]\nuse regex::Regex;\nextern crate rand;\nlet x = ;\nx\n";
+        assert_eq!(
+            parts(code),
+            [
+                (Place::CrateBegin, 2, "#![allow(unused)]", false),
+                (Place::Prefix, 3, "use regex::Regex;", false),
+                (Place::Prefix, 4, "extern crate rand;", false),
+                (Place::Body, 5, "let x = ;\nx\n", false),
+            ]
+        );
+        assert_eq!(split_snippet(code).externs, ["regex", "rand"]);
+    }
+
+    #[test]
+    fn programs() {
+        assert!(is_program("use std::env;\nfn main() {\n}\n"));
+        assert!(!is_program("fn helper() {}\nhelper();\n"));
+        assert!(!is_program("let main = 1;\n"));
+        // by text, if it does not parse
+        assert!(is_program("fn main() {\n    let x = ;\n}\n"));
+    }
+}
@@ -1,41 +1,159 @@
+use crate::compile::Wrapper;
+use crate::meta::version_req;
+use anyhow::Result;
 use lapp::Args;
+use semver::VersionReq;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
 pub struct State {
     pub build_static: bool,
     pub optimize: bool,
     pub exe: bool,
+    // build a test harness with rustc --test
+    pub test: bool,
+    // timing is pointless without optimization, even for dynamic builds
+    pub bench: bool,
     pub edition: String,
     pub verbose: bool,
     pub simplify: bool,
+    // don't show compiler errors, when failure is expected
+    pub quiet: bool,
     pub libc: bool,
     pub features: Vec<String>,
     pub link: Option<String>,
     pub cfg: Vec<String>,
     pub externs: Vec<String>,
+    // named codegen profile (--profile)
+    pub profile: Option<String>,
+    // crates which must be linked at a particular version (--versions)
+    pub versions: HashMap<String, VersionReq>,
+    // added to the program name, so that the builds made to compare
+    // editions or versions leave the script's own program alone
+    pub variant: String,
 }
 
 impl State {
-    pub fn exe(is_static: bool, optimized: bool, args: &Args) -> State {
+    pub fn exe(is_static: bool, optimized: bool, args: &Args) -> Result<State> {
         Self::make_state(is_static, optimized, true, args)
     }
 
-    fn make_state(build_static: bool, optimize: bool, exe: bool, args: &Args) -> State {
-        State {
+    fn make_state(build_static: bool, optimize: bool, exe: bool, args: &Args) -> Result<State> {
+        Ok(State {
             build_static,
             optimize,
             exe,
+            test: exe && args.get_bool("test"),
+            bench: exe && args.get_bool("bench"),
             edition: args.get_string("edition"),
             verbose: args.get_bool("verbose"),
             simplify: !args.get_bool("no-simplify"),
+            quiet: false,
             libc: args.get_bool("libc"),
             features: args.get_strings("features"),
             link: args.get_string_result("link").ok(),
             cfg: args.get_strings("cfg"),
-            externs: args.get_strings("extern"),
-        }
+            externs: args
+                .get_strings("extern")
+                .iter()
+                .map(|c| crate_name(c))
+                .collect(),
+            profile: Some(args.get_string("profile")).filter(|p| !p.is_empty()),
+            versions: crate_versions(args)?,
+            variant: String::new(),
+        })
     }
 
-    pub fn dll(optimized: bool, args: &Args) -> State {
+    pub fn dll(optimized: bool, args: &Args) -> Result<State> {
         Self::make_state(false, optimized, false, args)
     }
 }
+
+// crates a snippet asks for with -x, -X and -M
+pub struct SnippetCrates {
+    // all of them, since -X and -M imply -x
+    pub externs: Vec<String>,
+    pub wild: Vec<String>,
+    pub macros: HashSet<String>,
+}
+
+// `-x rand@0.8` asks for a particular version of a crate
+fn split_version(c: &str) -> Result<Option<(&str, VersionReq)>> {
+    match c.split_once('@') {
+        Some((name, vs)) => Ok(Some((name, version_req(vs)?))),
+        None => Ok(None),
+    }
+}
+
+fn crate_name(c: &str) -> String {
+    c.split_once('@').map_or(c, |(name, _)| name).to_string()
+}
+
+fn crate_versions(args: &Args) -> Result<HashMap<String, VersionReq>> {
+    let mut versions = HashMap::new();
+    for flag in ["extern", "wild", "macro"] {
+        for c in args.get_strings(flag) {
+            if let Some((name, req)) = split_version(&c)? {
+                versions.insert(name.to_string(), req);
+            }
+        }
+    }
+    Ok(versions)
+}
+
+impl SnippetCrates {
+    pub fn new(args: &Args) -> SnippetCrates {
+        let names = |flag| -> Vec<String> {
+            args.get_strings(flag)
+                .iter()
+                .map(|c| crate_name(c))
+                .collect()
+        };
+        let mut externs = names("extern");
+        let wild = names("wild");
+        let macros = names("macro");
+        externs.extend(wild.iter().cloned());
+        externs.extend(macros.iter().cloned());
+        SnippetCrates {
+            externs,
+            wild,
+            macros: macros.into_iter().collect(),
+        }
+    }
+}
+
+// everything needed to turn a snippet into a program
+pub struct SnippetOptions {
+    pub prelude: Vec<(PathBuf, String)>,
+    pub crates: SnippetCrates,
+    // from --prepend
+    pub body_prelude: String,
+    pub wrapper: Wrapper,
+}
+
+impl SnippetOptions {
+    pub fn new(args: &Args, prelude: Vec<(PathBuf, String)>) -> SnippetOptions {
+        let mut body_prelude = args.get_string("prepend");
+        if !body_prelude.is_empty() {
+            body_prelude.push(';');
+        }
+        SnippetOptions {
+            prelude: if args.get_bool("no-prelude") {
+                Vec::new()
+            } else {
+                prelude
+            },
+            crates: SnippetCrates::new(args),
+            body_prelude,
+            wrapper: if args.get_bool("test") {
+                Wrapper::Test
+            } else if args.get_bool("bench") {
+                Wrapper::Bench
+            } else {
+                Wrapper::Main {
+                    debug_errors: args.get_bool("error-debug"),
+                }
+            },
+        }
+    }
+}
@@ -29,3 +29,19 @@ pub fn split(txt: &str, delim: char) -> (&str, &str) {
         (txt, "")
     }
 }
+
+// like "1.5 MB"
+pub fn human_size(bytes: u64) -> String {
+    let units = ["bytes", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
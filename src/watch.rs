@@ -0,0 +1,94 @@
+// --watch: recompile and rerun a script whenever it, the prelude,
+// env.rs or any files it pulls in are modified
+use crate::cache::lookup_file_path;
+use crate::program::{Program, Running};
+use crate::snippet;
+use crate::state::{SnippetOptions, State};
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// where env.rs is found now, if the prelude is used at all
+fn env_file(file: &Path, options: &SnippetOptions) -> Option<PathBuf> {
+    if options.prelude.is_empty() {
+        None
+    } else {
+        lookup_file_path("env.rs", Some(&file.to_path_buf()))
+    }
+}
+
+// the files to watch, with their modification times. These are found
+// afresh each time, so that a new env.rs or included file is noticed.
+fn snapshot(file: &Path, options: &SnippetOptions) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut files = vec![file.to_path_buf()];
+    if let Ok(code) = fs::read_to_string(file) {
+        let dir = file.parent().unwrap_or(Path::new("."));
+        files.extend(snippet::dependencies(&code, dir));
+    }
+    // the runner prelude always comes first
+    files.extend(options.prelude.first().map(|(path, _)| path.clone()));
+    files.extend(env_file(file, options));
+    files
+        .into_iter()
+        .map(|f| {
+            let time = fs::metadata(&f).and_then(|m| m.modified()).ok();
+            (f, time)
+        })
+        .collect()
+}
+
+// compile and start the script
+fn build_and_start(
+    file: &Path,
+    options: &mut SnippetOptions,
+    state: &mut State,
+    args: &[String],
+    running: &mut Option<Running>,
+) -> Result<()> {
+    let code = fs::read_to_string(file).context("cannot read file")?;
+    // the prelude may have changed, and env.rs come or gone
+    let env = env_file(file, options);
+    options.prelude.truncate(1);
+    options
+        .prelude
+        .extend(env.map(|path| (path, String::new())));
+    for (path, contents) in options.prelude.iter_mut() {
+        *contents = fs::read_to_string(&path).context("cannot read prelude")?;
+    }
+    let prog = Program::new(code, Some(file), options, state)?;
+    if prog.compile(state)? {
+        *running = Some(prog.start(state, args)?);
+    }
+    Ok(())
+}
+
+pub fn watch(
+    file: &Path,
+    mut options: SnippetOptions,
+    state: &mut State,
+    args: &[String],
+) -> Result<()> {
+    let mut running = None;
+    loop {
+        // clear the screen
+        print!("\x1b[2J\x1b[H");
+        io::stdout().flush()?;
+        // taken before building, so changes made while compiling count
+        let watched = snapshot(file, &options);
+        if let Err(e) = build_and_start(file, &mut options, state, args, &mut running) {
+            eprintln!("error: {:#}", e);
+        }
+        while snapshot(file, &options) == watched {
+            thread::sleep(POLL_INTERVAL);
+        }
+        // the previous instance may well still be running
+        if let Some(r) = running.take() {
+            r.kill()?;
+        }
+    }
+}